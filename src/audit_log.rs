@@ -0,0 +1,441 @@
+use crate::html::HtmlOptions;
+use crate::html::CORE_THEME_CSS;
+use crate::model::snowflake;
+use crate::model::timestamp;
+use crate::storage::Storage;
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use serenity::http::Http;
+use serenity::model::channel::Channel;
+use serenity::model::guild::audit_log::AuditLogEntry;
+use serenity::model::guild::Guild;
+use serenity::model::id::UserId;
+use serenity::model::id::WebhookId;
+use serenity::model::user::User;
+use serenity::model::webhook::Webhook;
+use tracing::*;
+
+const AUDIT_LOG_TEMPLATE: &str = include_str!("html_templates/audit_log.liquid");
+
+/// The version of the format of [`AuditLogArchive`].
+const AUDIT_LOG_VERSION: u32 = 1;
+
+/// The most entries Discord returns in one request.
+const PAGE_SIZE: u8 = 100;
+
+/// A guild's audit log, as written by [`archive_audit_log`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditLogArchive {
+    pub schema_version: u32,
+    /// When the archive was last updated.
+    pub exported_at: DateTime<Utc>,
+    #[serde(with = "snowflake")]
+    pub guild_id: u64,
+    pub guild_name: String,
+    /// Every entry archived so far, oldest first.
+    pub entries: Vec<ArchivedAuditLogEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedAuditLogEntry {
+    #[serde(with = "snowflake")]
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Discord's number for the kind of action.
+    pub action_type: u8,
+    /// A description of the action, such as "banned".
+    pub action: String,
+    #[serde(with = "snowflake")]
+    pub actor_id: u64,
+    /// The tag of the user who took the action, when it was archived.
+    pub actor_name: Option<String>,
+    /// The ID of the user, channel, role, etc. that the action affected.
+    #[serde(with = "snowflake::option")]
+    pub target_id: Option<u64>,
+    /// The name of the target, when it was archived.
+    pub target_name: Option<String>,
+    pub reason: Option<String>,
+    pub changes: Vec<ArchivedAuditLogChange>,
+    /// Extra information about some actions, such as how many messages were deleted, in Discord's
+    /// format.
+    pub options: Option<Value>,
+}
+
+/// A change to one property of the target of an audit log entry.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedAuditLogChange {
+    pub key: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// What [`archive_audit_log`] saved.
+pub struct AuditLogArchiveLog {
+    /// How many entries weren't in the archive before.
+    pub new_entries: usize,
+    pub total_entries: usize,
+    /// Where the JSON and HTML files were saved, for showing to the user.
+    pub locations: Vec<String>,
+}
+
+/// Save the audit log of `guild` as JSON and an HTML timeline in [`Storage::output`].
+///
+/// Discord only keeps audit log entries for a limited time, so the archive of each guild is kept
+/// under a fixed name and new entries are added to it each time this runs. Entries that are no
+/// longer in the audit log stay in the archive.
+#[instrument(skip_all, fields(guild = %guild.name))]
+pub async fn archive_audit_log(
+    http: &Http,
+    guild: &Guild,
+    html: HtmlOptions,
+) -> Result<AuditLogArchiveLog> {
+    info!("Starting audit log archive");
+    let storage = Storage::output();
+    // Named by ID rather than by name, so that renaming the guild doesn't start a new archive
+    let stem = format!("audit-log-{}", guild.id);
+    let json_key = format!("{}.json", stem);
+    let html_key = format!("{}.html", stem);
+
+    let mut archive = match storage.exists(&json_key).await? {
+        true => serde_json::from_slice::<AuditLogArchive>(&storage.read(&json_key).await?)?,
+        false => AuditLogArchive {
+            schema_version: AUDIT_LOG_VERSION,
+            exported_at: Utc::now(),
+            guild_id: guild.id.0,
+            guild_name: guild.name.clone(),
+            entries: Vec::new(),
+        },
+    };
+    let newest_archived = archive.entries.iter().map(|entry| entry.id).max();
+
+    // Discord returns the newest entries first, so page backwards until reaching the entries
+    // already archived
+    let mut entries = BTreeMap::new();
+    let mut users = HashMap::new();
+    let mut webhooks = HashMap::new();
+    let mut before = None;
+    loop {
+        let page = guild
+            .id
+            .audit_logs(http, None, None, before, Some(PAGE_SIZE))
+            .await?;
+        trace!(entries = %page.entries.len(), "Fetched audit log page");
+
+        let done = page.entries.len() < PAGE_SIZE as usize
+            || page
+                .entries
+                .iter()
+                .any(|entry| newest_archived.is_some_and(|id| entry.id.0 <= id));
+        before = page.entries.iter().map(|entry| entry.id).min();
+        users.extend(page.users);
+        webhooks.extend(page.webhooks);
+        for entry in page.entries {
+            if newest_archived.is_none_or(|id| entry.id.0 > id) {
+                entries.insert(entry.id, entry);
+            }
+        }
+
+        if done || before.is_none() {
+            break;
+        }
+    }
+
+    let names = Names {
+        guild,
+        users: &users,
+        webhooks: &webhooks,
+    };
+    let new_entries = entries.len();
+    archive.entries.extend(
+        entries
+            .values()
+            .map(|entry| ArchivedAuditLogEntry::new(entry, &names)),
+    );
+    archive.exported_at = Utc::now();
+    archive.guild_name = guild.name.clone();
+
+    storage
+        .write(&json_key, serde_json::to_string_pretty(&archive)?)
+        .await?;
+    storage
+        .write(&html_key, render_html(&archive, guild, html)?)
+        .await?;
+
+    info!(%new_entries, total = %archive.entries.len(), "Audit log archive complete");
+
+    Ok(AuditLogArchiveLog {
+        new_entries,
+        total_entries: archive.entries.len(),
+        locations: vec![storage.location(&json_key), storage.location(&html_key)],
+    })
+}
+
+/// Looks up the names of the things audit log entries refer to.
+struct Names<'a> {
+    guild: &'a Guild,
+    /// The users mentioned in the audit log, which may no longer be members.
+    users: &'a HashMap<UserId, User>,
+    webhooks: &'a HashMap<WebhookId, Webhook>,
+}
+
+impl Names<'_> {
+    fn user(&self, id: u64) -> Option<String> {
+        let id = UserId(id);
+        self.users
+            .get(&id)
+            .or_else(|| self.guild.members.get(&id).map(|member| &member.user))
+            .map(User::tag)
+    }
+
+    fn channel(&self, id: u64) -> Option<String> {
+        let name = match self.guild.channels.get(&id.into())? {
+            Channel::Guild(channel) => channel.name.clone(),
+            Channel::Category(category) => category.name.clone(),
+            _ => return None,
+        };
+        Some(format!("#{}", name))
+    }
+
+    fn thread(&self, id: u64) -> Option<String> {
+        self.guild
+            .threads
+            .iter()
+            .find(|thread| thread.id.0 == id)
+            .map(|thread| format!("#{}", thread.name))
+            .or_else(|| self.channel(id))
+    }
+
+    /// The name of the target of an entry of `action_type` with ID `id`, if it still exists.
+    fn target(&self, action_type: u8, id: u64) -> Option<String> {
+        match action_type {
+            1 => Some(self.guild.name.clone()),
+            10..=15 | 73 => self.channel(id),
+            20..=28 | 72 | 74 | 75 | 140..=143 => self.user(id),
+            30..=32 => self
+                .guild
+                .roles
+                .get(&id.into())
+                .map(|role| format!("@{}", role.name)),
+            50..=52 => self
+                .webhooks
+                .get(&id.into())
+                .and_then(|webhook| webhook.name.clone()),
+            60..=62 => self
+                .guild
+                .emojis
+                .get(&id.into())
+                .map(|emoji| format!(":{}:", emoji.name)),
+            90..=92 => self
+                .guild
+                .stickers
+                .get(&id.into())
+                .map(|sticker| sticker.name.clone()),
+            110..=112 => self.thread(id),
+            _ => None,
+        }
+    }
+}
+
+impl ArchivedAuditLogEntry {
+    fn new(entry: &AuditLogEntry, names: &Names) -> Self {
+        let action_type = entry.action.num();
+        let changes = entry
+            .changes
+            .iter()
+            .flatten()
+            .filter_map(|change| {
+                // serenity serializes changes the way Discord sends them
+                let mut value = serde_json::to_value(change).ok()?;
+                Some(ArchivedAuditLogChange {
+                    key: value.get("key")?.as_str()?.to_owned(),
+                    old: value.get_mut("old_value").map(Value::take),
+                    new: value.get_mut("new_value").map(Value::take),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Deleted things aren't in the guild any more, but their old name is usually in the
+        // changes
+        let changed_name = changes
+            .iter()
+            .find(|change| change.key == "name")
+            .and_then(|change| change.new.as_ref().or(change.old.as_ref()))
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+
+        Self {
+            id: entry.id.0,
+            timestamp: timestamp(entry.id.created_at()),
+            action_type,
+            action: describe_action(action_type).to_owned(),
+            actor_id: entry.user_id.0,
+            actor_name: names.user(entry.user_id.0),
+            target_id: entry.target_id,
+            target_name: entry
+                .target_id
+                .and_then(|id| names.target(action_type, id))
+                .or(changed_name),
+            reason: entry.reason.clone(),
+            changes,
+            options: entry
+                .options
+                .as_ref()
+                .and_then(|options| serde_json::to_value(options).ok()),
+        }
+    }
+}
+
+/// A description of the audit log action with Discord's number `action_type`, to follow the name
+/// of whoever took it.
+fn describe_action(action_type: u8) -> &'static str {
+    match action_type {
+        1 => "updated the server",
+        10 => "created channel",
+        11 => "updated channel",
+        12 => "deleted channel",
+        13 => "added a permission overwrite to",
+        14 => "updated a permission overwrite on",
+        15 => "removed a permission overwrite from",
+        20 => "kicked",
+        21 => "pruned members",
+        22 => "banned",
+        23 => "unbanned",
+        24 => "updated member",
+        25 => "updated the roles of",
+        26 => "moved a member between voice channels",
+        27 => "disconnected a member from voice",
+        28 => "added bot",
+        30 => "created role",
+        31 => "updated role",
+        32 => "deleted role",
+        40 => "created an invite",
+        41 => "updated an invite",
+        42 => "deleted an invite",
+        50 => "created webhook",
+        51 => "updated webhook",
+        52 => "deleted webhook",
+        60 => "created emoji",
+        61 => "updated emoji",
+        62 => "deleted emoji",
+        72 => "deleted a message by",
+        73 => "bulk deleted messages in",
+        74 => "pinned a message by",
+        75 => "unpinned a message by",
+        80 => "added an integration",
+        81 => "updated an integration",
+        82 => "removed an integration",
+        83 => "started a stage",
+        84 => "updated a stage",
+        85 => "ended a stage",
+        90 => "created sticker",
+        91 => "updated sticker",
+        92 => "deleted sticker",
+        100 => "created an event",
+        101 => "updated an event",
+        102 => "cancelled an event",
+        110 => "created thread",
+        111 => "updated thread",
+        112 => "deleted thread",
+        121 => "updated the permissions of an application command",
+        140 => "created an AutoMod rule",
+        141 => "updated an AutoMod rule",
+        142 => "deleted an AutoMod rule",
+        143 => "had a message blocked by AutoMod:",
+        _ => "took an unknown action",
+    }
+}
+
+/// `archive` as an HTML timeline, newest first.
+fn render_html(archive: &AuditLogArchive, guild: &Guild, options: HtmlOptions) -> Result<String> {
+    let names = Names {
+        guild,
+        users: &HashMap::new(),
+        webhooks: &HashMap::new(),
+    };
+
+    let entries = archive
+        .entries
+        .iter()
+        .rev()
+        .map(|entry| {
+            let mut details = entry
+                .changes
+                .iter()
+                .map(|change| {
+                    format!(
+                        "{}: {} → {}",
+                        change.key,
+                        describe_value(change.old.as_ref()),
+                        describe_value(change.new.as_ref())
+                    )
+                })
+                .collect::<Vec<_>>();
+            if let Some(Value::Object(options)) = &entry.options {
+                for (key, value) in options {
+                    let value = match (key.as_str(), value.as_str()) {
+                        // Show the name of the channel if it still exists
+                        ("channel_id", Some(id)) => id
+                            .parse()
+                            .ok()
+                            .and_then(|id| names.channel(id))
+                            .unwrap_or_else(|| id.to_owned()),
+                        _ => describe_value(Some(value)),
+                    };
+                    details.push(format!("{}: {}", key, value));
+                }
+            }
+
+            liquid::object!({
+                "id": entry.id.to_string(),
+                "timestamp": entry
+                    .timestamp
+                    .with_timezone(&options.timezone)
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+                "actor": entry
+                    .actor_name
+                    .clone()
+                    .unwrap_or_else(|| entry.actor_id.to_string()),
+                "action": entry.action,
+                "target": match (&entry.target_name, entry.target_id) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(id)) => id.to_string(),
+                    (None, None) => String::new(),
+                },
+                "reason": entry.reason.as_deref().unwrap_or_default(),
+                "details": details,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let template = liquid::ParserBuilder::with_stdlib()
+        .build()?
+        .parse(AUDIT_LOG_TEMPLATE)?;
+    Ok(template.render(&liquid::object!({
+        "guild_name": archive.guild_name,
+        "core_css": CORE_THEME_CSS,
+        "theme_css": options.theme.css(),
+        "exported_at": archive
+            .exported_at
+            .with_timezone(&options.timezone)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        "entries": entries,
+    }))?)
+}
+
+/// `value` as shown in the HTML timeline: strings without quotes, and anything else as JSON.
+fn describe_value(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "(none)".to_owned(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
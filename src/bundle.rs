@@ -0,0 +1,180 @@
+use crate::config;
+use crate::html::HtmlOptions;
+use crate::model::Archive;
+use crate::storage::Storage;
+use crate::write_outputs;
+use crate::OutputMode;
+use crate::Result;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::DateTime;
+use chrono::Utc;
+use indoc::indoc;
+use serde::Serialize;
+use tracing::*;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use zip::ZipWriter;
+
+/// The zstd compression level used for `.tar.zst` bundles.
+const ZSTD_LEVEL: i32 = 9;
+
+/// A format to package the output of an archive into.
+#[derive(Debug, Clone, Copy)]
+pub enum BundleFormat {
+    Zip,
+    TarZst,
+}
+
+impl BundleFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            BundleFormat::Zip => "zip",
+            BundleFormat::TarZst => "tar.zst",
+        }
+    }
+}
+
+impl FromStr for BundleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "zip" => Ok(BundleFormat::Zip),
+            "tar.zst" => Ok(BundleFormat::TarZst),
+            _ => Err(format!(
+                indoc! { "
+                Invalid bundle format {}. Valid values are one of the following:
+                - zip
+                - tar.zst"
+                },
+                s
+            )),
+        }
+    }
+}
+
+/// A description of the contents of a bundle, stored in it as `manifest.json`.
+#[derive(Serialize)]
+struct Manifest<'a> {
+    schema_version: u32,
+    exported_at: DateTime<Utc>,
+    guild: &'a str,
+    channel: &'a str,
+    message_count: usize,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    name: String,
+    size: u64,
+}
+
+/// Write `archive` in the formats requested by `output_mode`, along with a manifest, into a single
+/// `{output_file_stem}.{zip,tar.zst}` in `storage`, returning its key.
+///
+/// The outputs are written to a staging directory in the output path first, and the bundle is
+/// only stored once it is complete, so a bundle in `storage` is never partially written.
+#[instrument(skip(archive, output_mode, html, storage))]
+pub async fn write_bundle(
+    archive: &Archive,
+    output_mode: OutputMode,
+    html: HtmlOptions,
+    format: BundleFormat,
+    storage: &Storage,
+    output_file_stem: &str,
+) -> Result<String> {
+    let staging = config::get()
+        .output_path
+        .join(format!(".{output_file_stem}.staging"));
+    if staging.exists() {
+        // Left over from a run that was interrupted
+        tokio::fs::remove_dir_all(&staging).await?;
+    }
+    tokio::fs::create_dir_all(&staging).await?;
+
+    let keys = write_outputs(
+        archive,
+        output_mode,
+        html,
+        &Storage::local(&staging),
+        output_file_stem,
+    )
+    .await?;
+    let files = keys.iter().map(|key| staging.join(key)).collect::<Vec<_>>();
+
+    let mut manifest = Manifest {
+        schema_version: archive.schema_version,
+        exported_at: archive.exported_at,
+        guild: &archive.guild.name,
+        channel: &archive.channel.name,
+        message_count: archive.messages.len(),
+        files: Vec::new(),
+    };
+    for path in &files {
+        manifest.files.push(ManifestFile {
+            name: file_name(path),
+            size: tokio::fs::metadata(path).await?.len(),
+        });
+    }
+    let manifest_path = staging.join("manifest.json");
+    tokio::fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?).await?;
+
+    let mut contents = vec![manifest_path];
+    contents.extend(files);
+
+    let key = format!("{}.{}", output_file_stem, format.extension());
+    let bundle_path = staging.join(&key);
+
+    let write_path = bundle_path.clone();
+    tokio::task::spawn_blocking(move || match format {
+        BundleFormat::Zip => write_zip(&contents, &write_path),
+        BundleFormat::TarZst => write_tar_zst(&contents, &write_path),
+    })
+    .await
+    .expect("Failed to join the task writing the bundle")?;
+
+    storage.write_file(&key, &bundle_path).await?;
+    tokio::fs::remove_dir_all(&staging).await?;
+
+    info!(location = %storage.location(&key), "Wrote bundle");
+    Ok(key)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("Bundled paths always have a file name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn write_zip(contents: &[PathBuf], destination: &Path) -> Result<()> {
+    let mut zip = ZipWriter::new(File::create(destination)?);
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+    for path in contents {
+        zip.start_file(file_name(path), options)?;
+        std::io::copy(&mut File::open(path)?, &mut zip)?;
+    }
+    zip.finish()?.sync_all()?;
+    Ok(())
+}
+
+fn write_tar_zst(contents: &[PathBuf], destination: &Path) -> Result<()> {
+    let encoder = zstd::Encoder::new(File::create(destination)?, ZSTD_LEVEL)?;
+    let mut tar = tar::Builder::new(encoder);
+    for path in contents {
+        tar.append_path_with_name(path, file_name(path))?;
+    }
+    let mut file = tar.into_inner()?.finish()?;
+    file.flush()?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::config;
+use crate::Result;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serenity::model::channel::GuildChannel;
+use serenity::model::channel::Message;
+use serenity::model::id::ChannelId;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+
+/// The channels that currently have an open checkpoint. Two archives of the same channel at once
+/// would interleave their pages in the same file.
+static OPEN: Lazy<Mutex<HashSet<ChannelId>>> = Lazy::new(Default::default);
+
+/// The messages downloaded so far from a channel, saved to disk one page at a time so that an
+/// interrupted download can carry on from where it stopped.
+///
+/// The checkpoint file is JSON Lines, where each line is a page of messages as returned by Discord
+/// (newest first), in the order they were downloaded.
+pub struct Checkpoint {
+    channel_id: ChannelId,
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint for `channel`, returning it along with any messages saved by a previous
+    /// download, newest first.
+    pub async fn open(channel: &GuildChannel) -> Result<(Self, Vec<Message>)> {
+        if !OPEN.lock().unwrap().insert(channel.id) {
+            return Err(format!("#{} is already being archived", channel.name).into());
+        }
+        // Construct this first, so that the channel is released if anything below fails
+        let checkpoint = Self {
+            channel_id: channel.id,
+            path: config::get()
+                .output_path
+                .join(".checkpoints")
+                .join(format!("{}.jsonl", channel.id)),
+        };
+
+        let contents = match tokio::fs::read_to_string(&checkpoint.path).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tokio::fs::create_dir_all(checkpoint.path.parent().unwrap()).await?;
+                return Ok((checkpoint, Vec::new()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut messages = Vec::new();
+        let mut valid_len = 0;
+        for (number, line) in contents.split_inclusive('\n').enumerate() {
+            match serde_json::from_str::<Vec<Message>>(line) {
+                Ok(page) => {
+                    messages.extend(page);
+                    valid_len += line.len();
+                }
+                Err(error) => {
+                    // Most likely the bot stopped part way through writing this page. Truncate it,
+                    // so that new pages are not appended after it, and it will be downloaded again.
+                    warn!(
+                        ?error,
+                        line = %number + 1,
+                        path = ?checkpoint.path,
+                        "Discarding unreadable checkpoint page"
+                    );
+                    tokio::fs::OpenOptions::new()
+                        .write(true)
+                        .open(&checkpoint.path)
+                        .await?
+                        .set_len(valid_len as u64)
+                        .await?;
+                    break;
+                }
+            }
+        }
+
+        info!(count = %messages.len(), path = ?checkpoint.path, "Resuming from checkpoint");
+
+        Ok((checkpoint, messages))
+    }
+
+    /// Append a page of messages to the checkpoint.
+    pub async fn save_page(&mut self, page: &[Message]) -> Result<()> {
+        let mut line = serde_json::to_string(page)?;
+        line.push('\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await?;
+
+        trace!(count = %page.len(), "Saved checkpoint page");
+        Ok(())
+    }
+
+    /// Delete the checkpoint, once the archive it was for has been written.
+    pub async fn remove(self) -> Result<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        trace!(path = ?self.path, "Removed checkpoint");
+        Ok(())
+    }
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        OPEN.lock().unwrap().remove(&self.channel_id);
+    }
+}
@@ -0,0 +1,424 @@
+use crate::bundle::BundleFormat;
+use crate::html::HtmlOptions;
+use crate::html::Theme;
+use crate::naming::FilenameTemplate;
+use crate::permissions;
+use crate::storage::Storage;
+use crate::storage::StorageOpt;
+use crate::OutputMode;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono_tz::Tz;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serenity::model::id::GuildId;
+use serenity::model::id::RoleId;
+use serenity::model::Permissions;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The configuration of the bot, or of a headless archive, loaded at startup.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration is loaded at startup")
+}
+
+/// Options that can be given both on the command line and in the configuration file. Those given
+/// on the command line take precedence.
+#[derive(clap::Args, Debug)]
+pub struct ConfigOpt {
+    /// A TOML configuration file. See the readme for the settings it can contain
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Read the token from this environment variable instead of a file
+    #[clap(long)]
+    pub token_env: Option<String>,
+    /// Package the output of each archive, along with a manifest, into a single file of this
+    /// format (`zip` or `tar.zst`)
+    #[clap(long)]
+    pub bundle: Option<BundleFormat>,
+    /// The colour scheme of HTML output (`dark` or `light`) [default: dark]
+    #[clap(long)]
+    pub theme: Option<Theme>,
+    /// The timezone timestamps are shown in in HTML output, e.g. `Europe/London` [default: UTC]
+    #[clap(long)]
+    pub timezone: Option<String>,
+    /// The name archives are written under, e.g. `{guild}-{channel}-{date}`. See the readme for
+    /// the placeholders available [default: {guild}-{channel}]
+    #[clap(long)]
+    pub filename_template: Option<FilenameTemplate>,
+    #[clap(flatten)]
+    pub storage: StorageOpt,
+}
+
+/// Settings given on the command line, which override the configuration file.
+#[derive(Default)]
+pub struct Overrides<'a> {
+    pub token_filename: Option<&'a Path>,
+    pub appid_filename: Option<&'a Path>,
+    pub output_path: Option<&'a Path>,
+    pub default_format: Option<OutputMode>,
+    pub required_permissions: Option<Permissions>,
+    pub max_jobs: Option<usize>,
+    pub max_jobs_per_guild: Option<usize>,
+}
+
+/// The contents of the configuration file. Every setting is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    token_file: Option<PathBuf>,
+    token_env: Option<String>,
+    application_id: Option<u64>,
+    output_path: Option<PathBuf>,
+    bundle: Option<String>,
+    max_jobs: Option<usize>,
+    max_jobs_per_guild: Option<usize>,
+    allowed_guilds: Option<Vec<u64>>,
+    // The defaults for every guild. These can't be a flattened `GuildFile`, as serde doesn't
+    // support `deny_unknown_fields` with `flatten`.
+    default_format: Option<OutputMode>,
+    theme: Option<Theme>,
+    timezone: Option<String>,
+    filename_template: Option<String>,
+    required_permissions: Option<String>,
+    allowed_roles: Option<Vec<u64>>,
+    guilds: Option<HashMap<String, GuildFile>>,
+}
+
+/// Settings that can be overridden for each guild, under `[guilds.<id>]`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct GuildFile {
+    default_format: Option<OutputMode>,
+    theme: Option<Theme>,
+    timezone: Option<String>,
+    filename_template: Option<String>,
+    required_permissions: Option<String>,
+    allowed_roles: Option<Vec<u64>>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub token: String,
+    /// Only needed to run the bot.
+    pub application_id: Option<u64>,
+    /// The directory the bot keeps its state in, and that archives are written to unless they are
+    /// stored elsewhere.
+    pub output_path: PathBuf,
+    /// Where archives are written to.
+    pub storage: Storage,
+    pub bundle: Option<BundleFormat>,
+    pub max_jobs: usize,
+    pub max_jobs_per_guild: usize,
+    /// The guilds the bot can be used in, or `None` for all of them.
+    allowed_guilds: Option<HashSet<GuildId>>,
+    defaults: GuildConfig,
+    guilds: HashMap<GuildId, GuildConfig>,
+}
+
+/// Settings that can differ between guilds.
+#[derive(Debug, Clone)]
+pub struct GuildConfig {
+    /// The format archives are written in if none is given.
+    pub default_format: OutputMode,
+    pub html: HtmlOptions,
+    /// The name archives are written under.
+    pub filename_template: FilenameTemplate,
+    /// The permissions a user needs to use the bot's commands.
+    pub required_permissions: Permissions,
+    /// A user needs at least one of these roles to use the bot's commands, unless it is empty.
+    pub allowed_roles: Vec<RoleId>,
+}
+
+impl Config {
+    /// The settings for `guild_id`.
+    pub fn guild(&self, guild_id: GuildId) -> &GuildConfig {
+        self.guilds.get(&guild_id).unwrap_or(&self.defaults)
+    }
+
+    /// The settings for guilds without their own.
+    pub fn defaults(&self) -> &GuildConfig {
+        &self.defaults
+    }
+
+    /// Whether any guild requires different permissions to use the bot's commands than the
+    /// defaults.
+    pub fn has_per_guild_permissions(&self) -> bool {
+        self.guilds
+            .values()
+            .any(|guild| guild.required_permissions != self.defaults.required_permissions)
+    }
+
+    pub fn is_guild_allowed(&self, guild_id: GuildId) -> bool {
+        self.allowed_guilds
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&guild_id))
+    }
+}
+
+/// Load the configuration from the file given in `options` (if any) and the command line, and make
+/// it available through [`get`].
+///
+/// Returns a description of every problem found, rather than stopping at the first.
+pub fn load(
+    options: &ConfigOpt,
+    overrides: Overrides,
+    needs_application_id: bool,
+) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    let file = match &options.config {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => match toml::from_str::<ConfigFile>(&contents) {
+                Ok(file) => file,
+                Err(e) => return Err(vec![format!("Invalid config file {:?}: {}", path, e)]),
+            },
+            Err(e) => {
+                return Err(vec![format!(
+                    "Failed to read config file {:?}: {}",
+                    path, e
+                )])
+            }
+        },
+        None => ConfigFile::default(),
+    };
+
+    let token = match (
+        overrides.token_filename,
+        &options.token_env,
+        &file.token_file,
+        &file.token_env,
+    ) {
+        (Some(path), _, _, _) => read_token_file(path, &mut errors),
+        (None, Some(var), _, _) => read_token_env(var, &mut errors),
+        (None, None, Some(path), _) => read_token_file(path, &mut errors),
+        (None, None, None, Some(var)) => read_token_env(var, &mut errors),
+        (None, None, None, None) => {
+            errors.push(
+                "No token given. Pass a token file or `--token-env`, or set `token_file` or \
+                `token_env` in the config file"
+                    .to_owned(),
+            );
+            String::new()
+        }
+    };
+
+    let application_id = match overrides.appid_filename {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => match contents.trim().parse::<u64>() {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    errors.push(format!("Invalid application id in {:?}", path));
+                    None
+                }
+            },
+            Err(e) => {
+                errors.push(format!(
+                    "Failed to read application id file {:?}: {}",
+                    path, e
+                ));
+                None
+            }
+        },
+        None => file.application_id,
+    };
+    if needs_application_id && overrides.appid_filename.is_none() && application_id.is_none() {
+        errors.push(
+            "No application id given. Pass an application id file, or set `application_id` in \
+            the config file"
+                .to_owned(),
+        );
+    }
+
+    let output_path = overrides
+        .output_path
+        .map(Path::to_owned)
+        .or(file.output_path)
+        .unwrap_or_else(|| PathBuf::from("/dev/shm"));
+    if !output_path.is_dir() {
+        errors.push(format!(
+            "Output path {:?} does not exist or is not a directory",
+            output_path
+        ));
+    }
+
+    let bundle = match (options.bundle, &file.bundle) {
+        (Some(bundle), _) => Some(bundle),
+        (None, Some(bundle)) => bundle
+            .parse()
+            .map_err(|e| errors.push(format!("`bundle`: {}", e)))
+            .ok(),
+        (None, None) => None,
+    };
+
+    let max_jobs = overrides.max_jobs.or(file.max_jobs).unwrap_or(2);
+    let max_jobs_per_guild = overrides
+        .max_jobs_per_guild
+        .or(file.max_jobs_per_guild)
+        .unwrap_or(1);
+    if max_jobs == 0 || max_jobs_per_guild == 0 {
+        errors.push("`max_jobs` and `max_jobs_per_guild` must be at least 1".to_owned());
+    }
+
+    let base = GuildConfig {
+        default_format: OutputMode::All,
+        html: HtmlOptions::default(),
+        filename_template: FilenameTemplate::default(),
+        required_permissions: Permissions::MANAGE_MESSAGES,
+        allowed_roles: Vec::new(),
+    };
+    let default_file = GuildFile {
+        default_format: file.default_format,
+        theme: file.theme,
+        timezone: file.timezone.clone(),
+        filename_template: file.filename_template.clone(),
+        required_permissions: file.required_permissions.clone(),
+        allowed_roles: file.allowed_roles.clone(),
+    };
+    let mut defaults = resolve_guild(&base, &default_file, "", &mut errors);
+    // Command line options apply to every guild, so they override per-guild settings too
+    let apply_overrides = |config: &mut GuildConfig, errors: &mut Vec<String>| {
+        if let Some(format) = overrides.default_format {
+            config.default_format = format;
+        }
+        if let Some(theme) = options.theme {
+            config.html.theme = theme;
+        }
+        if let Some(timezone) = &options.timezone {
+            if let Some(timezone) = parse_timezone(timezone, "--timezone", errors) {
+                config.html.timezone = timezone;
+            }
+        }
+        if let Some(template) = &options.filename_template {
+            config.filename_template = template.clone();
+        }
+        if let Some(permissions) = overrides.required_permissions {
+            config.required_permissions = permissions;
+        }
+    };
+    apply_overrides(&mut defaults, &mut errors);
+
+    let mut guilds = HashMap::new();
+    for (id, guild_file) in file.guilds.iter().flatten() {
+        let guild_id = match id.parse::<u64>() {
+            Ok(id) => GuildId(id),
+            Err(_) => {
+                errors.push(format!("`[guilds.{}]`: guild ids must be numbers", id));
+                continue;
+            }
+        };
+        let context = format!("[guilds.{}] ", id);
+        let mut guild = resolve_guild(&defaults, guild_file, &context, &mut errors);
+        // Any errors in the overrides have already been reported above
+        apply_overrides(&mut guild, &mut Vec::new());
+        guilds.insert(guild_id, guild);
+    }
+
+    let allowed_guilds = file
+        .allowed_guilds
+        .map(|ids| ids.into_iter().map(GuildId).collect());
+
+    let storage = match Storage::from_options(&options.storage, &output_path) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            errors.push(e.to_string());
+            None
+        }
+    };
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    CONFIG
+        .set(Config {
+            token,
+            application_id,
+            output_path,
+            storage: storage.expect("Storage is valid if there are no errors"),
+            bundle,
+            max_jobs,
+            max_jobs_per_guild,
+            allowed_guilds,
+            defaults,
+            guilds,
+        })
+        .expect("Configuration is only loaded once");
+    Ok(())
+}
+
+/// Apply the settings in `file` on top of `base`. `context` says where in the file they are, for
+/// error messages.
+fn resolve_guild(
+    base: &GuildConfig,
+    file: &GuildFile,
+    context: &str,
+    errors: &mut Vec<String>,
+) -> GuildConfig {
+    let mut config = base.clone();
+    if let Some(format) = file.default_format {
+        config.default_format = format;
+    }
+    if let Some(theme) = file.theme {
+        config.html.theme = theme;
+    }
+    if let Some(timezone) = &file.timezone {
+        let name = format!("{}`timezone`", context);
+        if let Some(timezone) = parse_timezone(timezone, &name, errors) {
+            config.html.timezone = timezone;
+        }
+    }
+    if let Some(template) = &file.filename_template {
+        match template.parse() {
+            Ok(template) => config.filename_template = template,
+            Err(e) => errors.push(format!("{}`filename_template`: {}", context, e)),
+        }
+    }
+    if let Some(permissions) = &file.required_permissions {
+        match permissions::parse_permissions(permissions) {
+            Ok(permissions) => config.required_permissions = permissions,
+            Err(e) => errors.push(format!("{}`required_permissions`: {}", context, e)),
+        }
+    }
+    if let Some(roles) = &file.allowed_roles {
+        config.allowed_roles = roles.iter().copied().map(RoleId).collect();
+    }
+    config
+}
+
+fn parse_timezone(timezone: &str, name: &str, errors: &mut Vec<String>) -> Option<Tz> {
+    match timezone.parse() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            errors.push(format!(
+                "{}: unknown timezone `{}`. Use a name from the tz database, e.g. `Europe/London`",
+                name, timezone
+            ));
+            None
+        }
+    }
+}
+
+fn read_token_file(path: &Path, errors: &mut Vec<String>) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(token) => token.trim().to_owned(),
+        Err(e) => {
+            errors.push(format!("Failed to read token file {:?}: {}", path, e));
+            String::new()
+        }
+    }
+}
+
+fn read_token_env(var: &str, errors: &mut Vec<String>) -> String {
+    match std::env::var(var) {
+        Ok(token) => token.trim().to_owned(),
+        Err(_) => {
+            errors.push(format!("Environment variable `{}` is not set", var));
+            String::new()
+        }
+    }
+}
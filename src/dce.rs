@@ -0,0 +1,470 @@
+use crate::model::Archive;
+use crate::model::ArchivedAttachment;
+use crate::model::ArchivedEmbed;
+use crate::model::ArchivedMember;
+use crate::model::ArchivedMessage;
+use crate::model::ArchivedReaction;
+use crate::model::ArchivedRole;
+use crate::model::ArchivedSticker;
+use crate::model::ArchivedUser;
+use crate::storage::Storage;
+use crate::Result;
+
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+use tracing::*;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Export<'a> {
+    guild: DceGuild<'a>,
+    channel: DceChannel<'a>,
+    date_range: DateRange,
+    exported_at: String,
+    messages: Vec<DceMessage<'a>>,
+    message_count: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceGuild<'a> {
+    id: String,
+    name: &'a str,
+    icon_url: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceChannel<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    category_id: Option<String>,
+    category: Option<String>,
+    name: &'a str,
+    topic: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct DateRange {
+    after: Option<String>,
+    before: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceMessage<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    timestamp: String,
+    timestamp_edited: Option<String>,
+    call_ended_timestamp: Option<String>,
+    is_pinned: bool,
+    content: &'a str,
+    author: DceUser<'a>,
+    attachments: Vec<DceAttachment<'a>>,
+    embeds: Vec<DceEmbed<'a>>,
+    stickers: Vec<DceSticker<'a>>,
+    reactions: Vec<DceReaction>,
+    mentions: Vec<DceUser<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<DceReference>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceUser<'a> {
+    id: String,
+    name: &'a str,
+    discriminator: String,
+    nickname: &'a str,
+    color: Option<String>,
+    is_bot: bool,
+    roles: Vec<DceRole<'a>>,
+    avatar_url: String,
+}
+
+#[derive(Serialize)]
+struct DceRole<'a> {
+    id: String,
+    name: &'a str,
+    color: Option<String>,
+    position: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceAttachment<'a> {
+    id: String,
+    url: &'a str,
+    file_name: &'a str,
+    file_size_bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceEmbed<'a> {
+    title: &'a str,
+    url: Option<&'a str>,
+    timestamp: Option<&'a str>,
+    description: &'a str,
+    color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<DceEmbedAuthor<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<DceEmbedImage<'a>>,
+    images: Vec<DceEmbedImage<'a>>,
+    fields: Vec<DceEmbedField<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    footer: Option<DceEmbedFooter<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceEmbedAuthor<'a> {
+    name: &'a str,
+    url: Option<&'a str>,
+    icon_url: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct DceEmbedImage<'a> {
+    url: &'a str,
+    width: Option<u64>,
+    height: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceEmbedField<'a> {
+    name: &'a str,
+    value: &'a str,
+    is_inline: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceEmbedFooter<'a> {
+    text: &'a str,
+    icon_url: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceSticker<'a> {
+    id: String,
+    name: &'a str,
+    format: &'static str,
+    source_url: String,
+}
+
+#[derive(Serialize)]
+struct DceReaction {
+    emoji: DceEmoji,
+    count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceEmoji {
+    id: Option<String>,
+    name: String,
+    code: String,
+    is_animated: bool,
+    image_url: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DceReference {
+    message_id: Option<String>,
+    channel_id: String,
+    guild_id: Option<String>,
+}
+
+/// Write the messages in the JSON format used by
+/// [DiscordChatExporter](https://github.com/Tyrrrz/DiscordChatExporter), so that tooling written
+/// for its exports can consume our archives.
+#[instrument(skip_all)]
+pub async fn write_dce_json(archive: &Archive, storage: &Storage, key: &str) -> Result<()> {
+    trace!("Entered DiscordChatExporter json writer");
+
+    let members: HashMap<u64, &ArchivedMember> =
+        archive.members.iter().map(|m| (m.user.id, m)).collect();
+    let users: HashMap<u64, &ArchivedUser> = archive.users.iter().map(|u| (u.id, u)).collect();
+    let roles: HashMap<u64, &ArchivedRole> =
+        archive.guild.roles.iter().map(|r| (r.id, r)).collect();
+
+    let context = Lookup {
+        members,
+        users,
+        roles,
+    };
+
+    let guild = &archive.guild;
+    let channel = &archive.channel;
+
+    // The format has no way to mark a message as deleted, so leave out those recorded by watching
+    // the channel, so that the export matches the channel as it was
+    let messages = archive
+        .messages
+        .iter()
+        .filter(|message| message.deleted_at.is_none())
+        .collect::<Vec<_>>();
+
+    let export = Export {
+        guild: DceGuild {
+            id: guild.id.to_string(),
+            name: &guild.name,
+            icon_url: guild.icon_url.clone(),
+        },
+        channel: DceChannel {
+            id: channel.id.to_string(),
+            kind: channel_kind(channel.kind),
+            category_id: channel.category_id.map(|x| x.to_string()),
+            category: channel.category_name.clone(),
+            name: &channel.name,
+            topic: channel.topic.as_deref(),
+        },
+        date_range: DateRange {
+            after: None,
+            before: None,
+        },
+        exported_at: archive.exported_at.to_rfc3339(),
+        messages: messages
+            .iter()
+            .map(|message| convert_message(message, &context))
+            .collect(),
+        message_count: messages.len(),
+    };
+
+    let output = serde_json::to_string_pretty(&export)?;
+    storage.write(key, output).await?;
+    info!("DiscordChatExporter JSON generation complete");
+    Ok(())
+}
+
+struct Lookup<'a> {
+    members: HashMap<u64, &'a ArchivedMember>,
+    users: HashMap<u64, &'a ArchivedUser>,
+    roles: HashMap<u64, &'a ArchivedRole>,
+}
+
+fn convert_message<'a>(message: &'a ArchivedMessage, lookup: &Lookup<'a>) -> DceMessage<'a> {
+    DceMessage {
+        id: message.id.to_string(),
+        kind: message_kind(message.kind),
+        timestamp: dce_timestamp(&message.timestamp),
+        timestamp_edited: message.edited_timestamp.as_ref().map(dce_timestamp),
+        call_ended_timestamp: None,
+        is_pinned: message.pinned,
+        content: &message.content,
+        author: convert_user(&message.author, lookup),
+        attachments: message.attachments.iter().map(convert_attachment).collect(),
+        embeds: message.embeds.iter().map(convert_embed).collect(),
+        stickers: message.stickers.iter().map(convert_sticker).collect(),
+        reactions: message.reactions.iter().map(convert_reaction).collect(),
+        mentions: message
+            .mentions
+            .iter()
+            .filter_map(|id| lookup.users.get(id))
+            .map(|user| convert_user(user, lookup))
+            .collect(),
+        reference: message.reference.as_ref().map(|r| DceReference {
+            message_id: r.message_id.map(|x| x.to_string()),
+            channel_id: r.channel_id.to_string(),
+            guild_id: r.guild_id.map(|x| x.to_string()),
+        }),
+    }
+}
+
+fn convert_user<'a>(user: &'a ArchivedUser, lookup: &Lookup<'a>) -> DceUser<'a> {
+    let member = lookup.members.get(&user.id);
+
+    let mut roles: Vec<&ArchivedRole> = member
+        .map(|m| {
+            m.roles
+                .iter()
+                .flat_map(|id| lookup.roles.get(id))
+                .copied()
+                .collect()
+        })
+        .unwrap_or_default();
+    roles.sort_unstable_by_key(|role| std::cmp::Reverse(role.position));
+
+    DceUser {
+        id: user.id.to_string(),
+        name: &user.name,
+        discriminator: format!("{:04}", user.discriminator),
+        nickname: member
+            .and_then(|m| m.nick.as_deref())
+            .unwrap_or(user.name.as_str()),
+        color: roles
+            .iter()
+            .find(|role| role.colour != 0)
+            .map(|role| hex_colour(role.colour)),
+        is_bot: user.bot,
+        roles: roles
+            .iter()
+            .map(|role| DceRole {
+                id: role.id.to_string(),
+                name: &role.name,
+                color: (role.colour != 0).then(|| hex_colour(role.colour)),
+                position: role.position,
+            })
+            .collect(),
+        avatar_url: user.avatar_url.clone(),
+    }
+}
+
+fn convert_attachment(attachment: &ArchivedAttachment) -> DceAttachment<'_> {
+    DceAttachment {
+        id: attachment.id.to_string(),
+        url: &attachment.url,
+        file_name: &attachment.filename,
+        file_size_bytes: attachment.size,
+    }
+}
+
+fn convert_embed(embed: &ArchivedEmbed) -> DceEmbed<'_> {
+    DceEmbed {
+        title: embed.title.as_deref().unwrap_or_default(),
+        url: embed.url.as_deref(),
+        timestamp: embed.timestamp.as_deref(),
+        description: embed.description.as_deref().unwrap_or_default(),
+        color: embed.colour.map(hex_colour),
+        author: embed.author_name.as_deref().map(|name| DceEmbedAuthor {
+            name,
+            url: embed.author_url.as_deref(),
+            icon_url: embed.author_icon_url.as_deref(),
+        }),
+        thumbnail: embed.thumbnail_url.as_deref().map(|url| DceEmbedImage {
+            url,
+            width: None,
+            height: None,
+        }),
+        images: embed
+            .image_url
+            .iter()
+            .map(|url| DceEmbedImage {
+                url,
+                width: None,
+                height: None,
+            })
+            .collect(),
+        fields: embed
+            .fields
+            .iter()
+            .map(|x| DceEmbedField {
+                name: &x.name,
+                value: &x.value,
+                is_inline: x.inline,
+            })
+            .collect(),
+        footer: embed.footer_text.as_deref().map(|text| DceEmbedFooter {
+            text,
+            icon_url: embed.footer_icon_url.as_deref(),
+        }),
+    }
+}
+
+fn convert_sticker(sticker: &ArchivedSticker) -> DceSticker<'_> {
+    let (format, ext) = match sticker.format.as_str() {
+        "png" => ("Png", "png"),
+        "apng" => ("Apng", "png"),
+        "lottie" => ("Lottie", "json"),
+        _ => ("Unknown", "png"),
+    };
+    DceSticker {
+        id: sticker.id.to_string(),
+        name: &sticker.name,
+        format,
+        source_url: format!("https://cdn.discordapp.com/stickers/{}.{}", sticker.id, ext),
+    }
+}
+
+fn convert_reaction(reaction: &ArchivedReaction) -> DceReaction {
+    let emoji = match reaction.emoji_id {
+        Some(id) => DceEmoji {
+            id: Some(id.to_string()),
+            name: reaction.emoji_name.clone(),
+            code: reaction.emoji_name.clone(),
+            is_animated: reaction.animated,
+            image_url: format!(
+                "https://cdn.discordapp.com/emojis/{}.{}",
+                id,
+                if reaction.animated { "gif" } else { "png" }
+            ),
+        },
+        None => DceEmoji {
+            id: None,
+            name: reaction.emoji_name.clone(),
+            code: reaction.emoji_name.clone(),
+            is_animated: false,
+            image_url: twemoji_url(&reaction.emoji_name),
+        },
+    };
+    DceReaction {
+        emoji,
+        count: reaction.count,
+    }
+}
+
+/// DiscordChatExporter links unicode emoji to their twemoji image, named after the emoji's
+/// codepoints with variation selectors removed.
+fn twemoji_url(emoji: &str) -> String {
+    let codepoints = emoji
+        .chars()
+        .filter(|&c| c != '\u{fe0f}')
+        .map(|c| format!("{:x}", c as u32))
+        .collect::<Vec<_>>()
+        .join("-");
+    format!("https://cdn.jsdelivr.net/gh/twitter/twemoji@latest/assets/svg/{codepoints}.svg")
+}
+
+fn hex_colour(colour: u32) -> String {
+    format!("#{:06X}", colour & 0xFFFFFF)
+}
+
+/// Maps Discord's numeric channel type to the name of DiscordChatExporter's `ChannelKind`.
+fn channel_kind(kind: u8) -> &'static str {
+    match kind {
+        0 => "GuildTextChat",
+        1 => "DirectTextChat",
+        2 => "GuildVoiceChat",
+        3 => "DirectGroupTextChat",
+        4 => "GuildCategory",
+        5 => "GuildNews",
+        10 => "GuildNewsThread",
+        11 => "GuildPublicThread",
+        12 => "GuildPrivateThread",
+        13 => "GuildStageVoice",
+        14 => "GuildDirectory",
+        15 => "GuildForum",
+        _ => "Unknown",
+    }
+}
+
+/// Maps Discord's numeric message type to the name of DiscordChatExporter's `MessageKind`.
+fn message_kind(kind: u8) -> &'static str {
+    match kind {
+        1 => "RecipientAdd",
+        2 => "RecipientRemove",
+        3 => "Call",
+        4 => "ChannelNameChange",
+        5 => "ChannelIconChange",
+        6 => "ChannelPinnedMessage",
+        7 => "GuildMemberJoin",
+        18 => "ThreadCreated",
+        19 => "Reply",
+        _ => "Default",
+    }
+}
+
+fn dce_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339()
+}
@@ -1,48 +1,331 @@
-use std::path::PathBuf;
-
 // Tracing appears to get angry without this `use`
 use std::file;
 
 use crate::file;
-use crate::OPTIONS;
+use crate::naming;
+use crate::storage::Storage;
+use crate::Result;
+
+use std::collections::HashMap;
 
+use chrono::DateTime;
 use chrono::Utc;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::model::guild::Emoji;
 use serenity::model::guild::Guild;
+use serenity::model::sticker::Sticker;
+use serenity::model::sticker::StickerFormatType;
+use serenity::model::user::User;
 use tracing::*;
 
+/// The name of the file describing the emoji in an emoji archive.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// The version of the format of [`EmojiManifest`].
+///
+/// - 2: added stickers
+const MANIFEST_VERSION: u32 = 2;
+
+/// The directory stickers are saved in, within an emoji archive.
+const STICKER_DIRECTORY: &str = "stickers";
+
+/// A description of an emoji archive, stored in it as [`MANIFEST_FILE`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmojiManifest {
+    pub schema_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub guild_id: u64,
+    pub guild_name: String,
+    pub emoji: Vec<ArchivedEmoji>,
+    #[serde(default)]
+    pub stickers: Vec<ArchivedSticker>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedEmoji {
+    pub id: u64,
+    pub name: String,
+    pub animated: bool,
+    /// Whether the emoji could be used when it was archived. Emoji become unavailable when a guild
+    /// loses the boosts needed for them.
+    pub available: bool,
+    /// Whether the emoji is managed by an integration, such as Twitch.
+    pub managed: bool,
+    /// The IDs of the roles allowed to use the emoji. If empty, anyone can use it.
+    pub roles: Vec<u64>,
+    /// Who uploaded the emoji, if the bot was allowed to see it.
+    pub creator: Option<EmojiCreator>,
+    /// The path of the image, relative to the manifest, or `None` if it could not be downloaded.
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedSticker {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    /// The names of the emoji Discord suggests the sticker for.
+    pub tags: Vec<String>,
+    /// The format of the image: `png`, `apng` or `lottie`.
+    pub format: String,
+    pub available: bool,
+    pub creator: Option<EmojiCreator>,
+    /// The path of the image, relative to the manifest, or `None` if it could not be downloaded.
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmojiCreator {
+    pub id: u64,
+    pub name: String,
+}
+
+impl From<&User> for EmojiCreator {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id.0,
+            name: user.tag(),
+        }
+    }
+}
+
+/// What [`archive_emoji`] saved.
+pub struct EmojiArchiveLog {
+    pub emoji: usize,
+    pub stickers: usize,
+    /// Where the archive was saved, for showing to the user.
+    pub location: String,
+}
+
+/// Download every emoji and sticker in `guild`, along with a manifest describing them.
 #[instrument(skip_all)]
-pub async fn archive_emoji(guild: Guild) -> (usize, PathBuf) {
+pub async fn archive_emoji(http: &Http, guild: Guild) -> Result<EmojiArchiveLog> {
     info!("Starting emoji archive");
-    let output_directory = OPTIONS.output_path.join(format!(
+    let storage = Storage::output();
+    let exported_at = Utc::now();
+    let output_directory = format!(
         "{}-{}",
-        guild.name.replace(char::is_whitespace, "-").to_lowercase(),
-        Utc::now().format("%Y-%m-%dT%H-%M-%S")
-    ));
+        naming::sanitize(&guild.name.replace(char::is_whitespace, "-").to_lowercase()),
+        exported_at.format("%Y-%m-%dT%H-%M-%S")
+    );
+
+    // The cached emoji don't say who created them, so fetch them again
+    let mut emojis = match guild.id.emojis(http).await {
+        Ok(x) => x,
+        Err(error) => {
+            warn!(
+                ?error,
+                "Failed to fetch emoji, using the cached ones instead"
+            );
+            guild.emojis.values().cloned().collect()
+        }
+    };
+    emojis.sort_by_key(|emoji| emoji.id);
+
+    let file_stems = file_stems(&emojis);
 
-    let mut fut: FuturesUnordered<_> = guild
-        .emojis
+    let mut fut: FuturesUnordered<_> = emojis
         .iter()
-        .map(|(_, emoji)| {
-            let url = emoji.url();
-            debug_assert!(!url.contains('?'), "URL should have no parameters");
-            let ext = &url[url
-                .rfind('.')
-                .expect("Emoji url should have a file extension")
-                + 1..];
-            let download_path = output_directory.join(format!("{}.{}", emoji.name, ext));
-            file::download_url(url, download_path)
+        .map(|emoji| {
+            let stem = &file_stems[&emoji.id.0];
+            let output_directory = &output_directory;
+            async move {
+                let result = download_emoji(emoji, storage, output_directory, stem).await;
+                (emoji.id.0, result)
+            }
         })
         .collect();
 
-    while let Some(x) = fut.next().await {
-        if let Err(e) = x {
-            error!(error = ?e, "Failed to download an emoji");
+    let mut files = HashMap::new();
+    while let Some((id, result)) = fut.next().await {
+        match result {
+            Ok(file) => {
+                files.insert(id, file);
+            }
+            Err(e) => error!(error = ?e, %id, "Failed to download an emoji"),
         }
     }
+    drop(fut);
 
-    info!(number = ?guild.emojis.len(), "Emoji download complete");
+    let count = files.len();
+    let manifest = EmojiManifest {
+        schema_version: MANIFEST_VERSION,
+        exported_at,
+        guild_id: guild.id.0,
+        guild_name: guild.name.clone(),
+        emoji: emojis
+            .iter()
+            .map(|emoji| ArchivedEmoji {
+                id: emoji.id.0,
+                name: emoji.name.clone(),
+                animated: emoji.animated,
+                available: emoji.available,
+                managed: emoji.managed,
+                roles: emoji.roles.iter().map(|x| x.0).collect(),
+                creator: emoji.user.as_ref().map(EmojiCreator::from),
+                file: files.remove(&emoji.id.0),
+            })
+            .collect(),
+        stickers: archive_stickers(http, &guild, storage, &output_directory).await,
+    };
+    storage
+        .write(
+            &format!("{}/{}", output_directory, MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )
+        .await?;
+
+    let stickers = manifest
+        .stickers
+        .iter()
+        .filter(|x| x.file.is_some())
+        .count();
+    info!(number = %count, total = %emojis.len(), %stickers, "Emoji download complete");
+
+    Ok(EmojiArchiveLog {
+        emoji: count,
+        stickers,
+        location: storage.location(&output_directory),
+    })
+}
+
+/// Download every sticker in `guild` into the stickers directory of `output_directory`.
+async fn archive_stickers(
+    http: &Http,
+    guild: &Guild,
+    storage: &Storage,
+    output_directory: &str,
+) -> Vec<ArchivedSticker> {
+    // As with emoji, the cached stickers don't say who created them
+    let mut stickers = match guild.id.stickers(http).await {
+        Ok(x) => x,
+        Err(error) => {
+            warn!(
+                ?error,
+                "Failed to fetch stickers, using the cached ones instead"
+            );
+            guild.stickers.values().cloned().collect()
+        }
+    };
+    stickers.sort_by_key(|sticker| sticker.id);
+
+    let mut archived = Vec::new();
+    for sticker in stickers {
+        let file = match download_sticker(&sticker, storage, output_directory).await {
+            Ok(x) => Some(x),
+            Err(e) => {
+                error!(error = ?e, id = %sticker.id, "Failed to download a sticker");
+                None
+            }
+        };
+        archived.push(ArchivedSticker {
+            id: sticker.id.0,
+            name: sticker.name.clone(),
+            description: sticker.description.clone(),
+            tags: sticker.tags.clone(),
+            format: match sticker.format_type {
+                StickerFormatType::Png => "png",
+                StickerFormatType::Apng => "apng",
+                StickerFormatType::Lottie => "lottie",
+                _ => "unknown",
+            }
+            .to_owned(),
+            available: sticker.available,
+            creator: sticker.user.as_ref().map(EmojiCreator::from),
+            file,
+        });
+    }
+    archived
+}
+
+/// Download `sticker` into `output_directory`, returning its path relative to it. Sticker names
+/// aren't unique, so stickers are saved under their ID.
+async fn download_sticker(
+    sticker: &Sticker,
+    storage: &Storage,
+    output_directory: &str,
+) -> Result<String> {
+    let url = sticker
+        .image_url()
+        .ok_or_else(|| format!("Sticker {} has an unknown format", sticker.id))?;
+    let download = file::download_url(&url).await?;
+
+    let extension = match sticker.format_type {
+        StickerFormatType::Lottie => "json",
+        _ => "png",
+    };
+    let path = format!(
+        "{}/{}-{}.{}",
+        STICKER_DIRECTORY,
+        naming::sanitize(&sticker.name),
+        sticker.id,
+        extension
+    );
+    storage
+        .write(&format!("{}/{}", output_directory, path), download.bytes)
+        .await?;
+    Ok(path)
+}
+
+/// The names to save each emoji under, without an extension, by ID. Several emoji in a guild can
+/// have the same name, so emoji whose names clash have their ID added. Names are compared ignoring
+/// case, as case-insensitive filesystems would treat `Foo` and `foo` as the same file.
+fn file_stems(emojis: &[Emoji]) -> HashMap<u64, String> {
+    let mut counts = HashMap::new();
+    for emoji in emojis {
+        *counts.entry(emoji.name.to_lowercase()).or_insert(0) += 1;
+    }
+
+    emojis
+        .iter()
+        .map(|emoji| {
+            let name = naming::sanitize(&emoji.name);
+            let stem = match counts[&emoji.name.to_lowercase()] {
+                1 => name,
+                _ => format!("{}-{}", name, emoji.id),
+            };
+            (emoji.id.0, stem)
+        })
+        .collect()
+}
+
+/// Download `emoji` into `output_directory`, returning the name of the file it was saved as.
+///
+/// Animated emoji are always saved as a GIF or WebP, so that they stay animated.
+async fn download_emoji(
+    emoji: &Emoji,
+    storage: &Storage,
+    output_directory: &str,
+    stem: &str,
+) -> Result<String> {
+    // `Emoji::url` asks for a GIF for animated emoji, and a PNG otherwise
+    let download = file::download_url(&emoji.url()).await?;
+
+    let extension = match download.content_type.as_deref() {
+        Some("image/gif") => "gif",
+        Some("image/webp") => "webp",
+        Some("image/png") if !emoji.animated => "png",
+        Some("image/jpeg") if !emoji.animated => "jpg",
+        None if !emoji.animated => "png",
+        content_type => {
+            return Err(format!(
+                "Discord returned an unexpected image type {:?} for emoji {} ({})",
+                content_type, emoji.name, emoji.id
+            )
+            .into())
+        }
+    };
 
-    (guild.emojis.len(), output_directory)
+    let file_name = format!("{}.{}", stem, extension);
+    storage
+        .write(
+            &format!("{}/{}", output_directory, file_name),
+            download.bytes,
+        )
+        .await?;
+    Ok(file_name)
 }
@@ -8,8 +8,9 @@ pub enum Error {
     #[error("Liquid error: {0}")]
     Liquid(#[from] liquid::Error),
 
+    // Boxed, as `serenity::Error` is large enough to bloat every `Result` in the crate
     #[error("Serenity error: {0}")]
-    Serenity(#[from] serenity::Error),
+    Serenity(Box<serenity::Error>),
 
     #[error("Serenity error: {0}")]
     Serde(#[from] serde_json::Error),
@@ -17,10 +18,32 @@ pub enum Error {
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
 
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    /// The user is not allowed to do what they asked. The message is shown to them as-is.
+    #[error("{0}")]
+    Forbidden(String),
+
+    /// Downloading messages from Discord failed. The message explains why, and is shown to the
+    /// user as-is.
+    #[error("{0}")]
+    Download(String),
+
+    /// The job was cancelled with `/archive_cancel`.
+    #[error("The archive was cancelled")]
+    Cancelled,
+
     #[error("{0}")]
     Custom(String),
 }
 
+impl From<serenity::Error> for Error {
+    fn from(e: serenity::Error) -> Self {
+        Self::Serenity(Box::new(e))
+    }
+}
+
 impl From<String> for Error {
     fn from(s: String) -> Self {
         Self::Custom(s)
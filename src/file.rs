@@ -1,31 +1,32 @@
 use crate::Result;
 
-use std::path::Path;
-
+use reqwest::header::CONTENT_TYPE;
 use tracing::*;
 
-#[instrument(skip(destination_filename))]
-pub async fn download_url<P>(url: String, destination_filename: P) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    let destination_filename = destination_filename.as_ref();
-    info!(%url, ?destination_filename, "Downloading file");
-
-    let response = reqwest::get(url.as_str()).await?;
+/// A file downloaded from a URL.
+pub struct Download {
+    pub bytes: Vec<u8>,
+    /// The MIME type the server gave for the file, if any, e.g. `image/gif`.
+    pub content_type: Option<String>,
+}
 
-    let destdir = destination_filename
-        .parent()
-        .expect("Destination path did not have a parent");
-    if !destdir.is_dir() {
-        tokio::fs::create_dir(destdir).await?;
-    };
+#[instrument]
+pub async fn download_url(url: &str) -> Result<Download> {
+    info!("Downloading file");
 
-    let bytes = response.bytes().await?;
+    let response = reqwest::get(url).await?.error_for_status()?;
 
-    tokio::fs::write(destination_filename, bytes).await?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.split(';').next().unwrap_or(x).trim().to_ascii_lowercase());
+    let bytes = response.bytes().await?.to_vec();
 
-    trace!("Download complete");
+    trace!(?content_type, size = %bytes.len(), "Download complete");
 
-    Ok(())
+    Ok(Download {
+        bytes,
+        content_type,
+    })
 }
@@ -1,25 +1,24 @@
+use crate::model::Archive;
+use crate::model::ArchivedMember;
+use crate::model::ArchivedMessage;
+use crate::model::ArchivedRole;
+use crate::model::ArchivedUser;
+use crate::storage::Storage;
 use crate::Result;
 
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::path::Path;
+use std::str::FromStr;
 use std::time::Instant;
 
+use chrono::SecondsFormat;
+use chrono_tz::Tz;
 use indoc::indoc;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serenity::model::channel::GuildChannel;
-use serenity::model::channel::Message;
-use serenity::model::guild::Guild;
-use serenity::model::guild::Member;
-use serenity::model::guild::Role;
-use serenity::model::id::ChannelId;
-use serenity::model::id::UserId;
-use serenity::model::user::User;
-use serenity::prelude::Context;
+use serde::Deserialize;
 use tracing::*;
 
-const CORE_THEME_CSS: &str = include_str!("html_templates/core.css");
+pub(crate) const CORE_THEME_CSS: &str = include_str!("html_templates/core.css");
 const DARK_THEME_CSS: &str = include_str!("html_templates/dark.css");
 const LIGHT_THEME_CSS: &str = include_str!("html_templates/light.css");
 const PREAMBLE_TEMPLATE: &str = include_str!("html_templates/preamble_template.liquid");
@@ -27,7 +26,6 @@ const POSTAMBLE_TEMPLATE: &str = include_str!("html_templates/postamble_template
 const MESSAGE_GROUP_TEMPLATE: &str = include_str!("html_templates/message_group.liquid");
 
 const IMAGE_FILE_EXTS: &[&str] = &[".jpg", ".jpeg", ".JPG", ".JPEG", ".png", ".PNG", ".gif"];
-const USE_DARK_MODE: bool = true;
 
 static CUSTOM_EMOJI_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(\\?)&lt;(a?):(\w+):(\d+)&gt;").unwrap());
@@ -59,13 +57,62 @@ pub fn prebuild_regexes() {
     trace!("Forced regexes");
 }
 
+/// The colour scheme of the HTML output.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// The stylesheet for the theme, to use alongside [`CORE_THEME_CSS`].
+    pub(crate) fn css(self) -> &'static str {
+        match self {
+            Theme::Dark => DARK_THEME_CSS,
+            Theme::Light => LIGHT_THEME_CSS,
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "dark" => Ok(Theme::Dark),
+            "light" => Ok(Theme::Light),
+            _ => Err(format!(
+                "Invalid theme {}. Valid values are `dark` and `light`",
+                s
+            )),
+        }
+    }
+}
+
+/// How the HTML output is presented.
+#[derive(Debug, Clone, Copy)]
+pub struct HtmlOptions {
+    pub theme: Theme,
+    /// The timezone timestamps are shown in.
+    pub timezone: Tz,
+}
+
+impl Default for HtmlOptions {
+    fn default() -> Self {
+        Self {
+            theme: Theme::Dark,
+            timezone: Tz::UTC,
+        }
+    }
+}
+
 #[instrument(skip_all)]
-pub async fn write_html<P: AsRef<Path>>(
-    ctx: &Context,
-    guild: &Guild,
-    channel: &GuildChannel,
-    messages: &[Message],
-    path: P,
+pub async fn write_html(
+    archive: &Archive,
+    options: HtmlOptions,
+    storage: &Storage,
+    key: &str,
 ) -> Result<()> {
     trace!("Entered HTML generator");
 
@@ -74,55 +121,73 @@ pub async fn write_html<P: AsRef<Path>>(
     let postamble_template = liquid_parser.parse(POSTAMBLE_TEMPLATE)?;
     let message_group_template = liquid_parser.parse(MESSAGE_GROUP_TEMPLATE)?;
 
-    let category_name = match channel.parent_id {
-        Some(x) => x.name(&ctx).await,
-        None => None,
-    };
+    let guild = &archive.guild;
+    let channel = &archive.channel;
 
     let liquid_objects = liquid::object!({
         "guild_name": &guild.name,
         "channel_name": &channel.name,
         "core_css": CORE_THEME_CSS,
-        "theme_css": if USE_DARK_MODE {DARK_THEME_CSS} else {LIGHT_THEME_CSS},
-        "guild_icon_url": guild.icon_url().unwrap_or_default(),
+        "theme_css": options.theme.css(),
+        "guild_icon_url": guild.icon_url.as_deref().unwrap_or_default(),
         "guild_icon_alt": get_acronym_from_str(guild.name.as_str()),
-        "category_name": category_name.unwrap_or_default(),
+        "category_name": channel.category_name.as_deref().unwrap_or_default(),
         "channel_topic": channel.topic.as_deref().unwrap_or_default(),
+        "pinned_only": archive.pinned_only,
+        "has_deleted": archive.messages.iter().any(|x| x.deleted_at.is_some()),
     });
 
     let mut html = preamble_template.render(&liquid_objects)?;
 
     trace!("Generated preamble");
 
-    let channels = guild.channels(&ctx).await?;
+    let message_renderer = MessageRenderer::new(archive);
 
-    let mut message_renderer = MessageRenderer::new(ctx, guild, channels).await;
+    let messages = &archive.messages;
 
     trace!("Begin generating message HTML");
     for (i, message) in messages.iter().enumerate() {
         let author = &message.author;
 
-        let author_highest_role = message_renderer
-            .get_highest_role_with_colour(&message.author, guild)
-            .await;
+        let author_highest_role = message_renderer.get_highest_role_with_colour(author);
 
-        let content = message_renderer.render_message(message).await;
+        let content = message_renderer.render_message(message);
 
         let message_liquid_objects = liquid::object!({
-            "author_avatar_url": author.face(),
+            "author_avatar_url": author.avatar_url,
             "author_username": author.name,
             "author_discriminator": format!("{:04}", author.discriminator),
-            "author_user_id": author.id.0,
+            "author_user_id": author.id,
             "author_name_colour": format!(
                 "rgb({}, {}, {})",
-                author_highest_role.map(|x| x.colour.r()).unwrap_or(255),
-                author_highest_role.map(|x| x.colour.g()).unwrap_or(255),
-                author_highest_role.map(|x| x.colour.b()).unwrap_or(255),
+                author_highest_role.map(|x| (x.colour >> 16) & 0xFF).unwrap_or(255),
+                author_highest_role.map(|x| (x.colour >> 8) & 0xFF).unwrap_or(255),
+                author_highest_role.map(|x| x.colour & 0xFF).unwrap_or(255),
                 ),
-            "author_nick": message_renderer.get_nickname(&message.author).await.unwrap_or(""),
-            "message_timestamp": message.timestamp,
+            "author_nick": message_renderer.get_nickname(author).unwrap_or(""),
+            "message_timestamp": message
+                .timestamp
+                .with_timezone(&options.timezone)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
             "message_content": content,
-            "message_id": message.id.0,
+            "message_id": message.id,
+            "message_pinned": message.pinned,
+            "message_deleted_timestamp": message
+                .deleted_at
+                .map(|x| x.with_timezone(&options.timezone).to_rfc3339_opts(SecondsFormat::Millis, true))
+                .unwrap_or_default(),
+            "message_revisions": message
+                .revisions
+                .iter()
+                .map(|revision| liquid::object!({
+                    "timestamp": revision
+                        .timestamp
+                        .with_timezone(&options.timezone)
+                        .to_rfc3339_opts(SecondsFormat::Millis, true),
+                    // Attachments are shown once, under the current version
+                    "content": message_renderer.render_content(&revision.content),
+                }))
+                .collect::<Vec<_>>(),
         });
 
         let message_group = message_group_template.render(&message_liquid_objects)?;
@@ -140,92 +205,94 @@ pub async fn write_html<P: AsRef<Path>>(
             .as_str(),
     );
 
-    trace!(path = ?path.as_ref(), "Writing html file");
-    tokio::fs::write(path, html).await?;
+    trace!(%key, "Writing html file");
+    storage.write(key, html).await?;
 
     info!("HTML generation complete");
 
     Ok(())
 }
 
-struct MessageRenderer<'context> {
-    channel_names: HashMap<u64, String>,
-    members: HashMap<UserId, Option<Member>>,
-    usernames: HashMap<UserId, Option<String>>,
-    guild: &'context Guild,
-    ctx: &'context Context,
+/// Renders messages using only the data stored in an [`Archive`], so that rendering does not
+/// require a connection to Discord.
+struct MessageRenderer<'archive> {
+    channel_names: HashMap<u64, &'archive str>,
+    members: HashMap<u64, &'archive ArchivedMember>,
+    users: HashMap<u64, &'archive ArchivedUser>,
+    roles: HashMap<u64, &'archive ArchivedRole>,
 }
 
-impl<'context> MessageRenderer<'context> {
+impl<'archive> MessageRenderer<'archive> {
     #[instrument(skip_all)]
-    async fn new(
-        ctx: &'context Context,
-        guild: &'context Guild,
-        channels: HashMap<ChannelId, GuildChannel>,
-    ) -> MessageRenderer<'context> {
-        trace!("Begin getting channel names");
-
-        let channel_names = channels
-            .into_iter()
-            .map(|(id, channel)| (id.0, channel.name))
-            .collect();
+    fn new(archive: &'archive Archive) -> MessageRenderer<'archive> {
+        trace!("Begin indexing archive");
 
         Self {
-            channel_names,
-            members: guild
-                .members(&ctx, None, None)
-                .await
-                .expect("Failed to retrieve members")
-                .into_iter()
-                .map(|m| (m.user.id, Some(m)))
+            channel_names: archive
+                .channels
+                .iter()
+                .map(|channel| (channel.id, channel.name.as_str()))
+                .collect(),
+            members: archive
+                .members
+                .iter()
+                .map(|member| (member.user.id, member))
+                .collect(),
+            users: archive.users.iter().map(|user| (user.id, user)).collect(),
+            roles: archive
+                .guild
+                .roles
+                .iter()
+                .map(|role| (role.id, role))
                 .collect(),
-            usernames: HashMap::new(),
-            guild,
-            ctx,
         }
     }
 
     #[instrument(skip_all)]
-    async fn get_username_cached(&mut self, user_id: &UserId) -> Option<&str> {
-        let entry = self.usernames.entry(*user_id);
-        let mut val = None;
-        if matches!(entry, Entry::Vacant(_)) {
-            match self
-                .ctx
-                .http
-                .get_user(*user_id.as_u64())
-                .await
-                .map(|x| x.name)
-            {
-                Ok(x) => val = Some(x),
-                Err(error) => {
-                    warn!(?user_id, ?error, "User id is not associated with a user",);
-                }
-            }
-        }
-        entry.or_insert(val).as_deref()
-    }
+    fn render_message(&self, message: &ArchivedMessage) -> String {
+        let start = Instant::now();
 
-    #[instrument(skip_all)]
-    async fn get_member_cached(&mut self, user_id: &UserId) -> Option<&Member> {
-        let entry = self.members.entry(*user_id);
-        let mut val = None;
-        if matches!(entry, Entry::Vacant(_)) {
-            match self.guild.member(&self.ctx, user_id).await {
-                Ok(x) => val = Some(x),
-                Err(error) => {
-                    warn!(?user_id, ?error, "User id not found in channel",);
+        let mut content = self.render_content(&message.content);
+
+        // Message attachments
+        if !message.attachments.is_empty() {
+            if !content.is_empty() {
+                content.push_str("<br>");
+            }
+            for attachment in message.attachments.iter() {
+                trace!(url = %attachment.url, "Found message attachment");
+                if IMAGE_FILE_EXTS.iter().any(|x| attachment.url.ends_with(x)) {
+                    content.push_str(&format!(
+                        indoc! { r#"
+                        <span class="chatlog__embed-image-container">
+                            <a href="{0:}" target="_blank">
+                                <img  
+                                    class="chatlog__embed-image"
+                                    title="{0:}"
+                                    src="{0:}"
+                                    alt="{0:}"
+                                />
+                            </a>
+                        </span><br>"#
+                        },
+                        attachment.url
+                    ));
+                } else {
+                    content.push_str(&format!(r#"<a href="{0}">{0}</a><br>"#, attachment.url));
                 }
             }
         }
-        entry.or_insert(val).as_ref()
+
+        let end = Instant::now();
+
+        trace!(time_taken = ?(end - start).as_nanos(), "Rendered message");
+
+        content
     }
 
-    #[instrument(skip_all)]
-    async fn render_message(&mut self, message: &Message) -> String {
-        let content = message.content.as_str();
-        trace!(%content, "Rendering message");
-        let start = Instant::now();
+    /// Render the markdown `content` of a message (or of an earlier version of one) as HTML.
+    fn render_content(&self, content: &str) -> String {
+        trace!(%content, "Rendering message content");
 
         // Ampersands break things
         let content = content.replace('&', "&amp;");
@@ -237,7 +304,7 @@ impl<'context> MessageRenderer<'context> {
         let content = content.replace('\n', "<br>");
 
         // Multiline code blocks
-        let mut content = {
+        let content = {
             // TODO Make this more unique, possibly random
             const URL_PLACEHOLDER: &str = "!!URL_PLACEHOLDER!!";
 
@@ -406,37 +473,26 @@ impl<'context> MessageRenderer<'context> {
                             let mut block: String = block.into();
 
                             // User mentions
-                            while let Some(m) = USER_MENTION_REGEX.find(&block) {
+                            while let Some(capts) = USER_MENTION_REGEX.captures(&block) {
+                                let m = capts.get(0).unwrap();
                                 trace!(mention = m.as_str(), "Found user mention");
-                                let uid: serenity::model::id::UserId = block
-                                    [m.start() + 6..m.end() - 4]
-                                    .parse::<u64>()
-                                    .unwrap()
-                                    .into();
-                                let member = self.get_member_cached(&uid).await;
-                                block = match member {
-                                    Some(x) => block.replace(
-                                        m.as_str(),
-                                        &format!(
-                                            "<span class=mention>@{}</span>",
-                                            get_member_nick(x)
-                                        ),
-                                    ),
-                                    None => {
-                                        let name = self.get_username_cached(&uid).await;
-                                        block.replace(
-                                            m.as_str(),
-                                            &match name {
-                                                Some(name) => {
-                                                    format!("<span class=mention>@{}</span>", name)
-                                                }
-                                                None => {
-                                                    format!("<span class=mention>@{}</span>", uid)
-                                                }
-                                            },
-                                        )
-                                    }
-                                }
+                                let uid: u64 = capts[1].parse().unwrap();
+                                let name = match self.members.get(&uid) {
+                                    Some(member) => Some(get_member_nick(member)),
+                                    None => self.users.get(&uid).map(|user| user.name.as_str()),
+                                };
+                                block = block.replace(
+                                    m.as_str(),
+                                    &match name {
+                                        Some(name) => {
+                                            format!("<span class=mention>@{}</span>", name)
+                                        }
+                                        None => {
+                                            warn!(user_id = %uid, "User mentioned that is not in the archive");
+                                            format!("<span class=mention>@{}</span>", uid)
+                                        }
+                                    },
+                                )
                             }
 
                             // URLs part 2
@@ -462,7 +518,7 @@ impl<'context> MessageRenderer<'context> {
                     trace!(%whole_block, "Found code block");
                     out.push_str(r#"<pre class="pre pre--multiline">"#);
                     let b = whole_block.find("<br>").and_then(|idx| {
-                        if whole_block[..idx].chars().all(|c| c.is_ascii()) {
+                        if whole_block[..idx].is_ascii() {
                             whole_block.get(idx + 4..)
                         } else {
                             None
@@ -479,70 +535,36 @@ impl<'context> MessageRenderer<'context> {
             out
         };
 
-        // Message attachments
-        if !message.attachments.is_empty() {
-            if !content.is_empty() {
-                content.push_str("<br>");
-            }
-            for attachment in message.attachments.iter() {
-                trace!(url = %attachment.url, "Found message attachment");
-                if IMAGE_FILE_EXTS.iter().any(|x| attachment.url.ends_with(x)) {
-                    content.push_str(&format!(
-                        indoc! { r#"
-                        <span class="chatlog__embed-image-container">
-                            <a href="{0:}" target="_blank">
-                                <img  
-                                    class="chatlog__embed-image"
-                                    title="{0:}"
-                                    src="{0:}"
-                                    alt="{0:}"
-                                />
-                            </a>
-                        </span><br>"#
-                        },
-                        attachment.url
-                    ));
-                } else {
-                    content.push_str(&format!(r#"<a href="{0}">{0}</a><br>"#, attachment.url));
-                }
-            }
-        }
-
-        let end = Instant::now();
-
-        trace!(time_taken = ?(end - start).as_nanos(), "Rendered message");
-
         content
     }
 
     #[instrument(skip_all)]
-    async fn get_nickname(&mut self, user: &User) -> Option<&str> {
-        self.get_member_cached(&user.id)
-            .await
-            .and_then(|x| x.nick.as_deref())
+    fn get_nickname(&self, user: &ArchivedUser) -> Option<&'archive str> {
+        self.members.get(&user.id).and_then(|x| x.nick.as_deref())
     }
 
     #[instrument(skip_all)]
-    async fn get_highest_role_with_colour(
-        &mut self,
-        user: &User,
-        guild: &'context Guild,
-    ) -> Option<&'context Role> {
-        let member = self.get_member_cached(&user.id).await?;
+    fn get_highest_role_with_colour(&self, user: &ArchivedUser) -> Option<&'archive ArchivedRole> {
+        let member = self.members.get(&user.id)?;
 
         let mut roles: Vec<_> = member
             .roles
             .iter()
-            .flat_map(|roleid| guild.roles.get(roleid))
+            .flat_map(|roleid| self.roles.get(roleid))
             .collect();
         roles.sort_unstable_by_key(|role| role.position);
 
-        roles.iter().rev().find(|role| role.colour.0 != 0).copied()
+        roles
+            .iter()
+            .rev()
+            .find(|role| role.colour != 0)
+            .copied()
+            .copied()
     }
 }
 
 #[inline]
-fn get_member_nick(member: &Member) -> &str {
+fn get_member_nick(member: &ArchivedMember) -> &str {
     member.nick.as_deref().unwrap_or(member.user.name.as_str())
 }
 
@@ -0,0 +1,182 @@
+use crate::config;
+use crate::error::Error;
+use crate::format_duration;
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use serenity::model::id::GuildId;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+static JOBS: Lazy<JobManager> = Lazy::new(|| {
+    let config = config::get();
+    JobManager::new(config.max_jobs, config.max_jobs_per_guild)
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Queued,
+    Running,
+}
+
+#[derive(Debug)]
+struct JobInfo {
+    guild_id: GuildId,
+    description: String,
+    state: JobState,
+    /// When the job was queued, or when it started running if it has.
+    since: Instant,
+    cancel: CancellationToken,
+}
+
+/// Queues archive jobs, limiting how many run at once both globally and per guild.
+struct JobManager {
+    next_id: AtomicU64,
+    global: Arc<Semaphore>,
+    per_guild_limit: usize,
+    per_guild: Mutex<HashMap<GuildId, Arc<Semaphore>>>,
+    jobs: Mutex<BTreeMap<u64, JobInfo>>,
+}
+
+impl JobManager {
+    fn new(global_limit: usize, per_guild_limit: usize) -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            global: Arc::new(Semaphore::new(global_limit)),
+            per_guild_limit,
+            per_guild: Mutex::new(HashMap::new()),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn guild_semaphore(&self, guild_id: GuildId) -> Arc<Semaphore> {
+        self.per_guild
+            .lock()
+            .unwrap()
+            .entry(guild_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_guild_limit)))
+            .clone()
+    }
+}
+
+/// A job that has been submitted to the queue. The job is removed from the queue when this is
+/// dropped.
+pub struct Job {
+    pub id: u64,
+    guild_id: GuildId,
+    cancel: CancellationToken,
+    _permits: Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)>,
+}
+
+impl Job {
+    /// Wait until there is capacity to run this job, then mark it as running.
+    ///
+    /// Returns [`Error::Cancelled`] if the job is cancelled while it is queued.
+    pub async fn start(&mut self) -> Result<()> {
+        let guild = JOBS.guild_semaphore(self.guild_id);
+
+        let permits = tokio::select! {
+            permits = async {
+                let guild = guild.acquire_owned().await;
+                let global = JOBS.global.clone().acquire_owned().await;
+                (guild, global)
+            } => permits,
+            _ = self.cancel.cancelled() => return Err(Error::Cancelled),
+        };
+
+        let (Ok(guild), Ok(global)) = permits else {
+            unreachable!("Job semaphores are never closed");
+        };
+        self._permits = Some((guild, global));
+
+        if let Some(info) = JOBS.jobs.lock().unwrap().get_mut(&self.id) {
+            info.state = JobState::Running;
+            info.since = Instant::now();
+        }
+
+        info!(id = %self.id, "Job started");
+        Ok(())
+    }
+
+    /// A token that is cancelled when `/archive_cancel` is used on this job.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        JOBS.jobs.lock().unwrap().remove(&self.id);
+        trace!(id = %self.id, "Job finished");
+    }
+}
+
+/// Add a job to the queue. Call [`Job::start`] to wait for it to be able to run.
+pub fn submit(guild_id: GuildId, description: String) -> Job {
+    let id = JOBS.next_id.fetch_add(1, Ordering::Relaxed);
+    let cancel = CancellationToken::new();
+
+    JOBS.jobs.lock().unwrap().insert(
+        id,
+        JobInfo {
+            guild_id,
+            description,
+            state: JobState::Queued,
+            since: Instant::now(),
+            cancel: cancel.clone(),
+        },
+    );
+
+    info!(%id, "Job queued");
+
+    Job {
+        id,
+        guild_id,
+        cancel,
+        _permits: None,
+    }
+}
+
+/// Cancel job `id` if it belongs to `guild_id`. Returns whether such a job existed.
+pub fn cancel(guild_id: GuildId, id: u64) -> bool {
+    match JOBS.jobs.lock().unwrap().get(&id) {
+        Some(info) if info.guild_id == guild_id => {
+            info!(%id, "Cancelling job");
+            info.cancel.cancel();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Describe the queued and running jobs in `guild_id`.
+pub fn status(guild_id: GuildId) -> Vec<String> {
+    JOBS.jobs
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, info)| info.guild_id == guild_id)
+        .map(|(id, info)| {
+            format!(
+                "`{}`: {} ({} for {})",
+                id,
+                info.description,
+                match info.state {
+                    JobState::Queued => "queued",
+                    JobState::Running => "running",
+                },
+                format_duration(info.since.elapsed()),
+            )
+        })
+        .collect()
+}
@@ -1,33 +1,15 @@
+use crate::model::Archive;
+use crate::storage::Storage;
 use crate::Result;
 
-use std::path::Path;
-
-use serde_json::json;
-use serenity::model::channel::Message;
-use serenity::model::guild::Guild;
-use serenity::model::prelude::GuildChannel;
-use serenity::prelude::Context;
 use tracing::*;
 
 #[instrument(skip_all)]
-pub async fn write_json<P: AsRef<Path>>(
-    _ctx: &Context,
-    guild: &Guild,
-    channel: &GuildChannel,
-    messages: &[Message],
-    path: P,
-) -> Result<()> {
+pub async fn write_json(archive: &Archive, storage: &Storage, key: &str) -> Result<()> {
     trace!("Entered json writer");
-    let json = json!({
-        "guild" : guild,
-        "channel" : channel,
-        "messages" : messages,
-    });
-    // let json = json!(guild);
 
-    let output = serde_json::to_string_pretty(&json)?;
-    tokio::fs::write(path, output).await?;
-    //serde_json::to_writer_pretty(file, &json)?;
+    let output = serde_json::to_string_pretty(archive)?;
+    storage.write(key, output).await?;
     info!("JSON generation complete");
     Ok(())
 }
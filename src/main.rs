@@ -1,36 +1,76 @@
+mod audit_log;
+mod bundle;
+mod checkpoint;
+mod config;
+mod dce;
 mod emoji;
 mod error;
 mod file;
 mod html;
+mod jobs;
 mod json;
-
+mod members;
+mod model;
+mod naming;
+mod permissions;
+mod progress;
+mod replay;
+mod restore;
+mod retry;
+mod schedule;
+mod storage;
+mod structure;
+mod upload;
+mod watch;
+
+use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 use std::time::Instant;
 
+use chrono_tz::Tz;
 use clap::Parser;
 use indoc::indoc;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
 use serenity::async_trait;
+use serenity::builder::CreateApplicationCommands;
+use serenity::http::Http;
 use serenity::model::application::command::Command;
 use serenity::model::application::command::CommandOptionType;
 use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
 use serenity::model::application::interaction::application_command::CommandDataOptionValue;
 use serenity::model::application::interaction::Interaction;
 use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::channel::AttachmentType;
 use serenity::model::channel::Channel;
 use serenity::model::channel::GuildChannel;
 use serenity::model::channel::Message;
+use serenity::model::event::MessageUpdateEvent;
 use serenity::model::gateway::Ready;
 use serenity::model::guild::Guild;
 use serenity::model::id::ChannelId;
+use serenity::model::id::GuildId;
+use serenity::model::id::MessageId;
+use serenity::model::Permissions;
 use serenity::prelude::*;
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 use tracing_subscriber::EnvFilter;
 
+use crate::checkpoint::Checkpoint;
+use crate::config::ConfigOpt;
 use crate::emoji::archive_emoji;
+use crate::error::Error;
+use crate::html::HtmlOptions;
+use crate::html::Theme;
+use crate::model::ArchivedGuild;
+use crate::progress::Progress;
+use crate::progress::ProgressSender;
+use crate::storage::Storage;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
@@ -38,7 +78,8 @@ const USAGE_STRING: &str = indoc! { "
     Invalid syntax.
     Correct usage is `!archive <channel> [mode]`, \
     where `channel` is the channel you want to archive, and `mode` \
-    is one of either `json`, `html`, or `all`."
+    is one of either `json`, `dce`, `html`, or `all`. If `mode` is omitted, the \
+    server's default format is used."
 };
 
 const REPLY_FAILURE: &str = "Failed to reply to message";
@@ -55,20 +96,129 @@ async fn main() {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let token = tokio::fs::read_to_string(&OPTIONS.token_filename)
-        .await
-        .expect("File does not exist");
-    let token = token.trim();
+    match &OPTIONS.command {
+        Subcommand::Schema => {
+            println!("{}", model::json_schema());
+        }
+        Subcommand::Migrate { archive } => {
+            if let Err(e) = migrate(archive).await {
+                error!(error = ?e, "Failed to migrate archive");
+                std::process::exit(1);
+            }
+            info!(path = ?archive, version = %model::SCHEMA_VERSION, "Migrated archive");
+        }
+        Subcommand::Render {
+            archive,
+            format,
+            output_path,
+            theme,
+            timezone,
+        } => {
+            tokio::spawn(async { html::prebuild_regexes() });
+            let html_options = HtmlOptions {
+                theme: *theme,
+                timezone: *timezone,
+            };
+            match render(archive, *format, html_options, output_path.as_deref()).await {
+                Ok(files_created) => {
+                    for file in files_created {
+                        println!("{}", file);
+                    }
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to render archive");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Subcommand::Archive {
+            token_filename,
+            guild,
+            channel,
+            format,
+            config,
+            output_path,
+        } => {
+            load_config(
+                config,
+                config::Overrides {
+                    token_filename: token_filename.as_deref(),
+                    output_path: output_path.as_deref(),
+                    default_format: *format,
+                    ..Default::default()
+                },
+                false,
+            );
+            tokio::spawn(async { html::prebuild_regexes() });
+            match archive_headless(GuildId(*guild), ChannelId(*channel)).await {
+                Ok(log) => {
+                    for file in log.files_created {
+                        println!("{}", Storage::output().location(&file));
+                    }
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to archive channel");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Subcommand::Bot {
+            token_filename,
+            appid_filename,
+            output_path,
+            required_permissions,
+            max_jobs,
+            max_jobs_per_guild,
+            default_format,
+            config,
+        } => {
+            load_config(
+                config,
+                config::Overrides {
+                    token_filename: token_filename.as_deref(),
+                    appid_filename: appid_filename.as_deref(),
+                    output_path: output_path.as_deref(),
+                    default_format: *default_format,
+                    required_permissions: *required_permissions,
+                    max_jobs: *max_jobs,
+                    max_jobs_per_guild: *max_jobs_per_guild,
+                },
+                true,
+            );
+            run_bot().await
+        }
+    }
+}
 
-    let application_id = tokio::fs::read_to_string(&OPTIONS.appid_filename)
-        .await
-        .expect("File does not exist")
-        .trim()
-        .parse::<u64>()
-        .expect("Invalid application_id");
+/// Load the configuration, exiting if it is invalid.
+fn load_config(options: &ConfigOpt, overrides: config::Overrides, needs_application_id: bool) {
+    if let Err(errors) = config::load(options, overrides, needs_application_id) {
+        for error in errors {
+            error!(%error, "Invalid configuration");
+        }
+        std::process::exit(1);
+    }
+}
+
+async fn run_bot() {
+    let config = config::get();
+    let token = &config.token;
+    let application_id = config
+        .application_id
+        .expect("The application id is required to load the bot's config");
 
     trace!(%token);
 
+    if let Err(error) = schedule::load().await {
+        error!(%error, "Failed to load schedules");
+        std::process::exit(1);
+    }
+
+    if let Err(error) = watch::load().await {
+        error!(%error, "Failed to load watched channels");
+        std::process::exit(1);
+    }
+
     tokio::spawn(async { html::prebuild_regexes() });
 
     let intents = GatewayIntents::all();
@@ -76,7 +226,7 @@ async fn main() {
     // Create a new instance of the Client, logging in as a bot. This will
     // automatically prepend your bot token with "Bot ", which is a requirement
     // by Discord for bot users.
-    let mut client = Client::builder(&token, intents)
+    let mut client = Client::builder(token, intents)
         .event_handler(Handler)
         .application_id(application_id)
         .await
@@ -93,10 +243,55 @@ async fn main() {
     }
 }
 
+/// Archive a channel using only the HTTP API, without connecting to the gateway.
+#[instrument]
+async fn archive_headless(guild_id: GuildId, channel_id: ChannelId) -> Result<ArchiveLog> {
+    let http = Http::new(&config::get().token);
+
+    let guild = guild_id.to_partial_guild(&http).await?;
+
+    let channel = channel_id
+        .to_channel(&http)
+        .await?
+        .guild()
+        .ok_or_else(|| format!("Channel {} is not a guild channel", channel_id))?;
+
+    if channel.guild_id != guild_id {
+        return Err(format!(
+            "Channel {} is not in guild {} ({})",
+            channel_id, guild_id, guild.name
+        )
+        .into());
+    }
+
+    let output_mode = config::get().guild(guild_id).default_format;
+
+    info!(
+        guild = %guild.name,
+        channel = %channel.name,
+        ?output_mode,
+        "Archive requested"
+    );
+
+    archive(
+        &http,
+        &channel,
+        &ArchivedGuild::from(&guild),
+        output_mode,
+        false,
+        None,
+        &CancellationToken::new(),
+    )
+    .await
+}
+
 struct ArchiveLog {
     download_time: Duration,
     render_time: Duration,
-    files_created: Vec<PathBuf>,
+    /// The name the files were written under, without an extension.
+    name: String,
+    /// The keys of the files created, in [`Storage::output`].
+    files_created: Vec<String>,
 }
 
 async fn handle_slash_command(
@@ -115,14 +310,21 @@ async fn handle_slash_command(
 
             match command.guild_id {
                 Some(guild_id) => {
+                    permissions::authorize(
+                        ctx,
+                        guild_id,
+                        command.channel_id,
+                        command.user.id,
+                        None,
+                    )
+                    .await?;
                     let guild = guild_id
-                        .to_guild_cached(&ctx)
+                        .to_guild_cached(ctx)
                         .ok_or_else(|| "Guild not found in cache".to_owned())?;
-                    let (n, output_path) = archive_emoji(guild).await;
+                    let log = archive_emoji(&ctx.http, guild).await?;
                     Ok(format!(
-                        "Archived {} emoji into `{}`",
-                        n,
-                        output_path.display(),
+                        "Archived {} emoji and {} stickers into `{}`",
+                        log.emoji, log.stickers, log.location
                     ))
                 }
                 None => Err("This command must be used within a guild".to_owned().into()),
@@ -137,35 +339,50 @@ async fn handle_slash_command(
                 .await
                 .expect(REPLY_FAILURE);
 
-            assert_eq!(
-                command.data.options.len(),
-                2,
-                "Command framework should only ever provide 2 args"
-            );
-
-            let channel = match command.data.options[0].resolved.as_ref() {
+            let channel = match get_option(command, "channel") {
                 Some(CommandDataOptionValue::Channel(c)) => c,
-                _ => unreachable!("Expected channel as first argument"),
+                _ => unreachable!("Expected channel argument"),
             }
             .id
             .to_channel(&ctx)
             .await?;
 
-            let mode = match command.data.options[1].resolved.as_ref() {
-                Some(CommandDataOptionValue::String(s)) => s
-                    .parse()
-                    .expect("Command framework should prevent invalid responses"),
-                _ => unreachable!("Expected format as second argument"),
+            let mode: Option<OutputMode> = match get_option(command, "output_format") {
+                Some(CommandDataOptionValue::String(s)) => Some(
+                    s.parse()
+                        .expect("Command framework should prevent invalid responses"),
+                ),
+                _ => None,
             };
 
+            let upload = matches!(
+                get_option(command, "upload"),
+                Some(CommandDataOptionValue::Boolean(true))
+            );
+            let pinned_only = matches!(
+                get_option(command, "pinned_only"),
+                Some(CommandDataOptionValue::Boolean(true))
+            );
+
             match channel {
                 Channel::Guild(channel) => match command.guild_id {
                     Some(guild_id) => {
+                        permissions::authorize(
+                            ctx,
+                            guild_id,
+                            command.channel_id,
+                            command.user.id,
+                            Some(&channel),
+                        )
+                        .await?;
+
                         let guild = guild_id
-                            .to_guild_cached(&ctx)
+                            .to_guild_cached(ctx)
                             // .to_partial_guild(&ctx)
                             .expect("Failed to fetch guild");
 
+                        let mode = mode.unwrap_or(config::get().guild(guild_id).default_format);
+
                         info!(
                             user = %format!(
                                 "{}#{:04}",
@@ -178,9 +395,58 @@ async fn handle_slash_command(
                             "Archive requested"
                         );
 
-                        Ok(archive(ctx, &channel, &guild, mode)
+                        let what = if pinned_only {
+                            "Archive of pinned messages in"
+                        } else {
+                            "Archive of"
+                        };
+                        let mut job = jobs::submit(
+                            guild_id,
+                            format!("{} <#{}> for <@{}>", what, channel.id, command.user.id),
+                        );
+                        command
+                            .edit_original_interaction_response(ctx, |builder| {
+                                builder.content(format!(
+                                    "Archive of #{} queued as job `{}`",
+                                    channel.name, job.id
+                                ))
+                            })
                             .await
-                            .map(archive_response)?)
+                            .expect(REPLY_FAILURE);
+                        job.start().await?;
+
+                        let archived_guild = ArchivedGuild::from(&guild);
+                        let (progress_sender, progress_receiver) = progress::channel();
+                        let progress_message = tokio::sync::Mutex::new(None);
+                        let (log, ()) = tokio::join!(
+                            archive(
+                                &ctx.http,
+                                &channel,
+                                &archived_guild,
+                                mode,
+                                pinned_only,
+                                Some(progress_sender),
+                                job.cancellation(),
+                            ),
+                            progress::report(progress_receiver, &channel, |text| {
+                                report_command_progress(ctx, command, &progress_message, text)
+                            }),
+                        );
+
+                        let log = log?;
+                        if !upload {
+                            return Ok(archive_response(log));
+                        }
+
+                        let note = upload::upload(
+                            ctx,
+                            command.channel_id,
+                            &log.files_created,
+                            &log.name,
+                            guild.premium_tier,
+                        )
+                        .await?;
+                        Ok(format!("{}\n{}", archive_response(log), note))
                     }
                     None => {
                         error!("Command used outside of a guild channel");
@@ -199,21 +465,539 @@ async fn handle_slash_command(
                 }
             }
         }
+        "schedule_archive" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            let channel = match get_option(command, "channel") {
+                Some(CommandDataOptionValue::Channel(c)) => c.id,
+                _ => unreachable!("Expected channel argument"),
+            };
+            let target = channel.to_channel(&ctx).await?.guild().ok_or_else(|| {
+                "Error: Argument `channel` must be a text channel in this guild.".to_owned()
+            })?;
+            permissions::authorize(
+                ctx,
+                guild_id,
+                command.channel_id,
+                command.user.id,
+                Some(&target),
+            )
+            .await?;
+            let output_mode = match get_option(command, "output_format") {
+                Some(CommandDataOptionValue::String(s)) => s
+                    .parse()
+                    .expect("Command framework should prevent invalid responses"),
+                _ => unreachable!("Expected output_format argument"),
+            };
+            let weekday = match get_option(command, "day") {
+                Some(CommandDataOptionValue::String(s)) if s == "every_day" => None,
+                Some(CommandDataOptionValue::String(s)) => Some(
+                    s.parse()
+                        .expect("Command framework should prevent invalid responses"),
+                ),
+                _ => unreachable!("Expected day argument"),
+            };
+            let time = match get_option(command, "time") {
+                Some(CommandDataOptionValue::String(s)) => {
+                    chrono::NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| {
+                        format!("Invalid time `{}`. Times must be of the form `HH:MM`.", s)
+                    })?
+                }
+                _ => unreachable!("Expected time argument"),
+            };
+            // Default to logging to the channel the command was used in
+            let log_channel = match get_option(command, "log_channel") {
+                Some(CommandDataOptionValue::Channel(c)) => c.id,
+                _ => command.channel_id,
+            };
+
+            let schedule =
+                schedule::add_schedule(guild_id, channel, log_channel, output_mode, weekday, time)
+                    .await?;
+
+            Ok(format!("Scheduled archive {}", schedule.describe()))
+        }
+        "unschedule_archive" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let id = match get_option(command, "id") {
+                Some(CommandDataOptionValue::Integer(i)) => *i as u64,
+                _ => unreachable!("Expected id argument"),
+            };
+
+            match schedule::remove_schedule(guild_id, id).await? {
+                Some(schedule) => Ok(format!("Removed scheduled archive {}", schedule.describe())),
+                None => Err(format!("There is no scheduled archive `{}` in this guild", id).into()),
+            }
+        }
+        "archive_status" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let jobs = jobs::status(guild_id);
+            if jobs.is_empty() {
+                Ok("There are no archive jobs queued or running in this guild".to_owned())
+            } else {
+                Ok(format!(
+                    "Archive jobs:\n{}",
+                    jobs.iter()
+                        .map(|job| format!("- {}", job))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ))
+            }
+        }
+        "archive_cancel" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let id = match get_option(command, "id") {
+                Some(CommandDataOptionValue::Integer(i)) => *i as u64,
+                _ => unreachable!("Expected id argument"),
+            };
+
+            if jobs::cancel(guild_id, id) {
+                Ok(format!("Cancelling archive job `{}`", id))
+            } else {
+                Err(format!("There is no archive job `{}` in this guild", id).into())
+            }
+        }
+        "archive_structure" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+            let log = structure::archive_structure(&ctx.http, &guild).await?;
+            Ok(format!(
+                "Archived {} roles and {} channels into `{}`",
+                log.roles, log.channels, log.location
+            ))
+        }
+        "archive_members" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+            let log = members::archive_members(&ctx.http, &guild).await?;
+            Ok(format!(
+                "Archived {} members into {}",
+                log.members,
+                log.locations
+                    .iter()
+                    .map(|x| format!("`{}`", x))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ))
+        }
+        "archive_audit_log" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+            permissions::require_guild_permissions(
+                ctx,
+                guild_id,
+                command.user.id,
+                Permissions::VIEW_AUDIT_LOG,
+            )
+            .await?;
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+            let log =
+                audit_log::archive_audit_log(&ctx.http, &guild, config::get().guild(guild_id).html)
+                    .await?;
+            Ok(format!(
+                "Archived {} new audit log entries ({} in total) into {}",
+                log.new_entries,
+                log.total_entries,
+                log.locations
+                    .iter()
+                    .map(|x| format!("`{}`", x))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ))
+        }
+        "restore_structure" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+            permissions::require_guild_permissions(
+                ctx,
+                guild_id,
+                command.user.id,
+                Permissions::MANAGE_ROLES | Permissions::MANAGE_CHANNELS,
+            )
+            .await?;
+
+            let directory = match get_option(command, "archive") {
+                Some(CommandDataOptionValue::String(s)) => s,
+                _ => unreachable!("Expected archive argument"),
+            };
+            let dry_run = match get_option(command, "dry_run") {
+                Some(CommandDataOptionValue::Boolean(b)) => *b,
+                _ => true,
+            };
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+            let log = restore::restore_structure(&ctx.http, &guild, directory, dry_run).await?;
+
+            let filename = match dry_run {
+                true => "restore-plan.txt",
+                false => "restore-log.txt",
+            };
+            command
+                .create_followup_message(ctx, |builder| {
+                    builder.add_file(AttachmentType::Bytes {
+                        data: log.changes.join("\n").into_bytes().into(),
+                        filename: filename.to_owned(),
+                    })
+                })
+                .await?;
+            Ok(log.summary)
+        }
+        "restore_emoji" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+            permissions::require_guild_permissions(
+                ctx,
+                guild_id,
+                command.user.id,
+                Permissions::MANAGE_EMOJIS_AND_STICKERS,
+            )
+            .await?;
+
+            let directory = match get_option(command, "archive") {
+                Some(CommandDataOptionValue::String(s)) => s,
+                _ => unreachable!("Expected archive argument"),
+            };
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+            restore::restore_emoji(&ctx.http, &guild, directory).await
+        }
+        "replay_archive" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            let archive_key = match get_option(command, "archive") {
+                Some(CommandDataOptionValue::String(s)) => s,
+                _ => unreachable!("Expected archive argument"),
+            };
+            let target = match get_option(command, "channel") {
+                Some(CommandDataOptionValue::Channel(c)) => c,
+                _ => unreachable!("Expected channel argument"),
+            }
+            .id
+            .to_channel(&ctx)
+            .await?;
+            let target = match target {
+                Channel::Guild(channel) if channel.guild_id == guild_id => channel,
+                _ => {
+                    return Err(
+                        "Error: Argument `channel` must be a text channel in this guild."
+                            .to_owned()
+                            .into(),
+                    )
+                }
+            };
+
+            permissions::authorize(
+                ctx,
+                guild_id,
+                command.channel_id,
+                command.user.id,
+                Some(&target),
+            )
+            .await?;
+            permissions::require_guild_permissions(
+                ctx,
+                guild_id,
+                command.user.id,
+                Permissions::MANAGE_WEBHOOKS,
+            )
+            .await?;
+
+            let guild = guild_id
+                .to_guild_cached(ctx)
+                .ok_or_else(|| "Guild not found in cache".to_owned())?;
+
+            let mut job = jobs::submit(
+                guild_id,
+                format!(
+                    "Replay of `{}` into <#{}> for <@{}>",
+                    archive_key, target.id, command.user.id
+                ),
+            );
+            command
+                .edit_original_interaction_response(ctx, |builder| {
+                    builder.content(format!(
+                        "Replay of `{}` queued as job `{}`",
+                        archive_key, job.id
+                    ))
+                })
+                .await
+                .expect(REPLY_FAILURE);
+            job.start().await?;
+
+            replay::replay(
+                ctx,
+                command,
+                archive_key,
+                &target,
+                guild.premium_tier,
+                job.cancellation(),
+            )
+            .await
+        }
+        "watch" | "unwatch" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            let channel = match get_option(command, "channel") {
+                Some(CommandDataOptionValue::Channel(c)) => c.id,
+                _ => unreachable!("Expected channel argument"),
+            };
+            let target = channel.to_channel(&ctx).await?.guild().ok_or_else(|| {
+                "Error: Argument `channel` must be a text channel in this guild.".to_owned()
+            })?;
+            permissions::authorize(
+                ctx,
+                guild_id,
+                command.channel_id,
+                command.user.id,
+                Some(&target),
+            )
+            .await?;
+
+            if command.data.name == "watch" {
+                match watch::add_watch(guild_id, channel, command.user.id).await? {
+                    Some(_) => Ok(format!(
+                        "Now recording messages sent, edited and deleted in <#{}>",
+                        channel
+                    )),
+                    None => Err(format!("<#{}> is already being watched", channel).into()),
+                }
+            } else {
+                match watch::remove_watch(guild_id, channel).await? {
+                    Some(_) => Ok(format!(
+                        "Stopped recording <#{}>. Its log has been kept",
+                        channel
+                    )),
+                    None => Err(format!("<#{}> is not being watched", channel).into()),
+                }
+            }
+        }
+        "watched_channels" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let watches = watch::list_watches(guild_id).await;
+            if watches.is_empty() {
+                Ok("No channels in this guild are being watched".to_owned())
+            } else {
+                Ok(format!(
+                    "Watched channels:\n{}",
+                    watches
+                        .iter()
+                        .map(|w| format!("- {}", w.describe()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ))
+            }
+        }
+        "scheduled_archives" => {
+            command
+                .create_interaction_response(&ctx, |reponse_builder| {
+                    reponse_builder.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                })
+                .await
+                .expect(REPLY_FAILURE);
+
+            let guild_id = command
+                .guild_id
+                .ok_or_else(|| "This command must be used within a guild".to_owned())?;
+
+            permissions::authorize(ctx, guild_id, command.channel_id, command.user.id, None)
+                .await?;
+
+            let schedules = schedule::list_schedules(guild_id).await;
+            if schedules.is_empty() {
+                Ok("There are no scheduled archives in this guild".to_owned())
+            } else {
+                Ok(format!(
+                    "Scheduled archives:\n{}",
+                    schedules
+                        .iter()
+                        .map(|s| format!("- {}", s.describe()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ))
+            }
+        }
         _ => Err("Error: Invalid command".to_owned().into()),
     }
 }
 
+/// Get the value of the option `name` passed to a slash command.
+fn get_option<'a>(
+    command: &'a ApplicationCommandInteraction,
+    name: &str,
+) -> Option<&'a CommandDataOptionValue> {
+    command
+        .data
+        .options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.resolved.as_ref())
+}
+
 async fn handle_archive_message(ctx: &Context, msg: &Message) -> Result<()> {
     if msg.content == "!archive_emoji" {
-        let guild = msg
+        let guild_id = msg
             .guild_id
-            .ok_or_else(|| "This command must be used from within a guild".to_owned())?
-            .to_guild_cached(&ctx)
+            .ok_or_else(|| "This command must be used from within a guild".to_owned())?;
+        permissions::authorize(ctx, guild_id, msg.channel_id, msg.author.id, None).await?;
+        let guild = guild_id
+            .to_guild_cached(ctx)
             .ok_or_else(|| "Guild not found in cache".to_owned())?;
-        let (n, output_path) = emoji::archive_emoji(guild).await;
+        let log = emoji::archive_emoji(&ctx.http, guild).await?;
         msg.reply(
             &ctx,
-            format!("Archived {} emoji into `{}`", n, output_path.display(),),
+            format!(
+                "Archived {} emoji and {} stickers into `{}`",
+                log.emoji, log.stickers, log.location
+            ),
         )
         .await
         .expect(REPLY_FAILURE);
@@ -229,30 +1013,45 @@ async fn handle_archive_message(ctx: &Context, msg: &Message) -> Result<()> {
         };
 
         let channel_id_str = &capts[1];
-        let mode = capts[2].parse()?;
+        let mode: Option<OutputMode> = capts.get(2).map(|x| x.as_str().parse()).transpose()?;
         trace!(channel_id = %channel_id_str, ?mode, "Command parsed");
 
-        let channel = match ChannelId::from_str(channel_id_str) {
+        let channel_id = match ChannelId::from_str(channel_id_str) {
             Ok(x) => x,
             Err(e) => {
                 error!(channel_id = %channel_id_str, error = ?e, "Invalid channel id");
                 return Err(format!("Invalid channel id {}.", channel_id_str).into());
             }
-        }
-        .to_channel(&ctx)
-        .await
-        .expect("Channel not found")
-        .guild()
-        .expect("Invalid channel type");
+        };
+        let channel = match channel_id.to_channel(&ctx).await {
+            Ok(Channel::Guild(channel)) => channel,
+            Ok(_) => return Err(format!("<#{}> is not a server channel.", channel_id).into()),
+            Err(e) => {
+                warn!(%channel_id, error = ?e, "Failed to fetch channel");
+                return Err(format!("Channel {} not found.", channel_id).into());
+            }
+        };
 
         let guild = match msg.guild_id {
-            Some(guild_id) => guild_id.to_guild_cached(&ctx).unwrap(),
+            Some(guild_id) => {
+                permissions::authorize(
+                    ctx,
+                    guild_id,
+                    msg.channel_id,
+                    msg.author.id,
+                    Some(&channel),
+                )
+                .await?;
+                guild_id.to_guild_cached(ctx).unwrap()
+            }
             None => {
                 error!(?channel, "Channel is not a guild channel");
                 return Err("This bot must be used in a guild channel".to_owned().into());
             }
         };
 
+        let mode = mode.unwrap_or(config::get().guild(guild.id).default_format);
+
         info!(
             user = %format!("{}#{:04}", msg.author.name, msg.author.discriminator),
             guild = %guild.name,
@@ -261,67 +1060,117 @@ async fn handle_archive_message(ctx: &Context, msg: &Message) -> Result<()> {
             "Archive requested"
         );
 
-        let response = archive(ctx, &channel, &guild, mode)
+        let mut job = jobs::submit(
+            guild.id,
+            format!("Archive of <#{}> for <@{}>", channel.id, msg.author.id),
+        );
+        let progress_message = msg
+            .reply(
+                &ctx,
+                format!("Archive of #{} queued as job `{}`", channel.name, job.id),
+            )
             .await
-            .map(archive_response)?;
+            .expect(REPLY_FAILURE);
+        job.start().await?;
+
+        let archived_guild = ArchivedGuild::from(&guild);
+        let (progress_sender, progress_receiver) = progress::channel();
+        let (log, ()) = tokio::join!(
+            archive(
+                &ctx.http,
+                &channel,
+                &archived_guild,
+                mode,
+                false,
+                Some(progress_sender),
+                job.cancellation(),
+            ),
+            progress::report(progress_receiver, &channel, |text| async move {
+                if let Err(error) = progress_message
+                    .channel_id
+                    .edit_message(ctx, progress_message.id, |m| m.content(text))
+                    .await
+                {
+                    warn!(?error, "Failed to report progress");
+                }
+            }),
+        );
+
+        let response = log.map(archive_response)?;
 
         msg.reply(&ctx, response).await.expect(REPLY_FAILURE);
     }
     Ok(())
 }
 
+/// The discord api limits us to retrieving 100 messages at a time
+///
+/// See <https://discord.com/developers/docs/resources/channel#get-channel-messages>
+const MESSAGE_DOWNLOAD_LIMIT: u64 = 100;
+
+/// Download every message in `channel`, oldest first.
+///
+/// `messages` are the messages saved in `checkpoint` by a previous, interrupted download, newest
+/// first. Only messages newer or older than these are downloaded, and each page of older messages
+/// is saved to `checkpoint` as it arrives.
 async fn download_channel_messages(
-    ctx: &Context,
+    http: &Http,
     channel: &GuildChannel,
+    checkpoint: &mut Checkpoint,
+    mut messages: Vec<Message>,
+    progress: Option<&ProgressSender>,
+    cancel: &CancellationToken,
 ) -> Result<(Vec<Message>, Duration)> {
-    trace!("Begin downloading messages");
+    trace!(saved_count = %messages.len(), "Begin downloading messages");
     let start = Instant::now();
 
-    /// The discord api limits us to retrieving 100 messages at a time
-    ///
-    /// See <https://discord.com/developers/docs/resources/channel#get-channel-messages>
-    const MESSAGE_DOWNLOAD_LIMIT: u64 = 100;
+    // Catch up on messages sent since the checkpoint was saved. These are not saved to the
+    // checkpoint, as the tail of the channel is appended to it below, and there are usually few
+    // of them.
+    if let Some(saved_newest) = messages.first().map(|m| m.id) {
+        let mut newer = Vec::new();
+        let mut before = None;
+        loop {
+            let page = fetch_page(http, channel, before, progress, cancel).await?;
+            let complete = page.len() != MESSAGE_DOWNLOAD_LIMIT as usize
+                || page.last().is_none_or(|m| m.id <= saved_newest);
+            before = page.last().map(|m| m.id);
+            newer.extend(page.into_iter().filter(|m| m.id > saved_newest));
+            if complete {
+                break;
+            }
+        }
+        trace!(download_count = %newer.len(), "Downloaded messages newer than checkpoint");
+        newer.append(&mut messages);
+        messages = newer;
+    }
 
-    // Download the first 100 messages.
-    let mut messages = channel
-        .messages(&ctx, |r| r.limit(MESSAGE_DOWNLOAD_LIMIT))
-        .await?;
+    loop {
+        let before = messages.last().map(|m| m.id);
+        let new_msgs = fetch_page(http, channel, before, progress, cancel).await?;
+        checkpoint.save_page(&new_msgs).await?;
+        let recv_count = new_msgs.len();
 
-    trace!(download_count = %messages.len());
+        messages.extend(new_msgs);
 
-    // Don't attempt to download more messages if zero were downloaded before.
-    if !messages.is_empty() {
-        loop {
-            let last_msg = messages.last().unwrap();
-            let new_msgs = channel
-                .id
-                .messages(&ctx, |r| {
-                    r.before(last_msg.id).limit(MESSAGE_DOWNLOAD_LIMIT)
-                })
-                .await;
-            let new_msgs = match new_msgs {
-                Ok(x) => x,
-                Err(e) => {
-                    warn!(
-                        error = ?e,
-                        download_count= %messages.len(),
-                        "While trying to download messages, \
-                        Discord returned an error. Waiting 5 seconds before retrying",
-                    );
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    continue;
-                }
-            };
-            let recv_count = new_msgs.len();
+        trace!(download_count = %messages.len());
 
-            messages.extend(new_msgs.into_iter());
+        if let (Some(newest), Some(oldest)) = (messages.first(), messages.last()) {
+            progress::send(
+                progress,
+                Progress::Fetched {
+                    count: messages.len(),
+                    newest: model::timestamp(newest.timestamp),
+                    oldest: model::timestamp(oldest.timestamp),
+                },
+            );
+        }
 
-            // If the api sends fewer than `MESSAGE_DOWNLOAD_LIMIT` messages, we have fetched all
-            // the messages in the channel
-            if recv_count != MESSAGE_DOWNLOAD_LIMIT as usize {
-                messages.reverse();
-                break;
-            }
+        // If the api sends fewer than `MESSAGE_DOWNLOAD_LIMIT` messages, we have fetched all
+        // the messages in the channel
+        if recv_count != MESSAGE_DOWNLOAD_LIMIT as usize {
+            messages.reverse();
+            break;
         }
     }
 
@@ -331,40 +1180,162 @@ async fn download_channel_messages(
     Ok((messages, download_time))
 }
 
+/// Fetch the page of messages in `channel` sent before `before`, or the newest messages if it is
+/// `None`. Temporary errors from Discord are retried with exponential backoff.
+async fn fetch_page(
+    http: &Http,
+    channel: &GuildChannel,
+    before: Option<MessageId>,
+    progress: Option<&ProgressSender>,
+    cancel: &CancellationToken,
+) -> Result<Vec<Message>> {
+    let mut backoff = retry::Backoff::default();
+    loop {
+        let page = tokio::select! {
+            page = channel.id.messages(http, |r| {
+                if let Some(before) = before {
+                    r.before(before);
+                }
+                r.limit(MESSAGE_DOWNLOAD_LIMIT)
+            }) => page,
+            _ = cancel.cancelled() => return Err(Error::Cancelled),
+        };
+        let error = match page {
+            Ok(page) => return Ok(page),
+            Err(e) => e,
+        };
+
+        if !retry::is_retryable(&error) {
+            error!(
+                ?error,
+                ?before,
+                "Discord returned an error that cannot be retried"
+            );
+            return Err(Error::Download(retry::describe_failure(&error, channel)));
+        }
+
+        let delay = match backoff.next_delay() {
+            Some(x) => x,
+            None => {
+                error!(?error, ?before, "Giving up downloading messages");
+                return Err(Error::Download(format!(
+                    "{}\nGave up after {} attempts. Archiving the channel again will resume \
+                    from where this stopped.",
+                    retry::describe_failure(&error, channel),
+                    backoff.attempts(),
+                )));
+            }
+        };
+
+        warn!(
+            ?error,
+            ?before,
+            attempt = %backoff.attempts(),
+            ?delay,
+            "While trying to download messages, Discord returned an error. Retrying",
+        );
+        progress::send(progress, Progress::Waiting { duration: delay });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = cancel.cancelled() => return Err(Error::Cancelled),
+        }
+    }
+}
+
+/// Fetch the pinned messages in `channel`, oldest first.
+async fn download_pinned_messages(
+    http: &Http,
+    channel: &GuildChannel,
+    cancel: &CancellationToken,
+) -> Result<(Vec<Message>, Duration)> {
+    trace!("Begin downloading pinned messages");
+    let start = Instant::now();
+
+    let mut messages = tokio::select! {
+        pins = channel.id.pins(http) => pins.map_err(|error| {
+            error!(?error, "Failed to download pinned messages");
+            Error::Download(retry::describe_failure(&error, channel))
+        })?,
+        _ = cancel.cancelled() => return Err(Error::Cancelled),
+    };
+    // Discord returns the most recently pinned first, which isn't necessarily the newest
+    messages.sort_by_key(|message| message.id);
+
+    Ok((messages, Instant::now() - start))
+}
+
+/// Archive `channel`, or only its pinned messages if `pinned_only`.
 #[instrument(skip_all)]
 async fn archive(
-    ctx: &Context,
+    http: &Http,
     channel: &GuildChannel,
-    guild: &Guild,
+    guild: &ArchivedGuild,
     output_mode: OutputMode,
+    pinned_only: bool,
+    progress: Option<ProgressSender>,
+    cancel: &CancellationToken,
 ) -> Result<ArchiveLog> {
-    let (messages, download_time) = download_channel_messages(ctx, channel).await?;
+    // Pinned messages are fetched in one request, so there is nothing to checkpoint
+    let mut checkpoint = None;
+    let (messages, download_time) = if pinned_only {
+        download_pinned_messages(http, channel, cancel).await?
+    } else {
+        let (mut opened, saved) = Checkpoint::open(channel).await?;
+        let downloaded =
+            download_channel_messages(http, channel, &mut opened, saved, progress.as_ref(), cancel)
+                .await?;
+        checkpoint = Some(opened);
+        downloaded
+    };
+    progress::send(progress.as_ref(), Progress::Rendering);
+    // Dropping the sender tells the receiver that there will be no more progress updates
+    drop(progress);
     info!(
         count = %messages.len(),
         time_taken = ?download_time,
         "Downloaded messages"
     );
 
-    let output_file_stem = format!(
-        "{}-{}",
-        guild.name.replace(char::is_whitespace, "_"),
-        channel.name.replace(char::is_whitespace, "_"),
-    );
-
-    let mut files_created = Vec::new();
-
     let start = Instant::now();
 
-    if output_mode.do_json() {
-        let output_path = OPTIONS.output_path.join(format!("{output_file_stem}.json"));
-        json::write_json(ctx, guild, channel, &messages, &output_path).await?;
-        files_created.push(output_path);
+    let mut archive = model::Archive::collect(http, guild, channel, &messages, pinned_only).await?;
+    // Deleted messages are never pinned, so the log only matters to full archives
+    if !pinned_only {
+        watch::apply_log(&mut archive).await?;
     }
-
-    if output_mode.do_html() {
-        let output_path = OPTIONS.output_path.join(format!("{output_file_stem}.html"));
-        html::write_html(ctx, guild, channel, &messages, &output_path).await?;
-        files_created.push(output_path);
+    let guild_config = config::get().guild(channel.guild_id);
+    let html = guild_config.html;
+    let name = if pinned_only {
+        format!("{}-pins", guild_config.filename_template.render(&archive))
+    } else {
+        guild_config.filename_template.render(&archive)
+    };
+    let claimed = naming::claim(Storage::output(), &name).await?;
+    let files_created = match config::get().bundle {
+        Some(format) => vec![
+            bundle::write_bundle(
+                &archive,
+                output_mode,
+                html,
+                format,
+                Storage::output(),
+                &claimed.name,
+            )
+            .await?,
+        ],
+        None => {
+            write_outputs(
+                &archive,
+                output_mode,
+                html,
+                Storage::output(),
+                &claimed.name,
+            )
+            .await?
+        }
+    };
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.remove().await?;
     }
 
     let end = Instant::now();
@@ -375,35 +1346,188 @@ async fn archive(
     Ok(ArchiveLog {
         download_time,
         render_time,
+        name: claimed.name.clone(),
         files_created,
     })
 }
 
+/// Write `archive` to `storage` in each of the formats requested by `output_mode`, returning the
+/// keys of the files created.
+async fn write_outputs(
+    archive: &model::Archive,
+    output_mode: OutputMode,
+    html: HtmlOptions,
+    storage: &Storage,
+    output_file_stem: &str,
+) -> Result<Vec<String>> {
+    let mut files_created = Vec::new();
+
+    if output_mode.do_json() {
+        let key = format!("{output_file_stem}.json");
+        json::write_json(archive, storage, &key).await?;
+        files_created.push(key);
+    }
+
+    if output_mode.do_dce() {
+        let key = format!("{output_file_stem}.dce.json");
+        dce::write_dce_json(archive, storage, &key).await?;
+        files_created.push(key);
+    }
+
+    if output_mode.do_html() {
+        let key = format!("{output_file_stem}.html");
+        html::write_html(archive, html, storage, &key).await?;
+        files_created.push(key);
+    }
+
+    Ok(files_created)
+}
+
+/// Upgrade an existing JSON archive to the current schema version, in place.
+///
+/// The migrated archive is written to a temporary file and renamed over the original, so a
+/// failure part way through leaves the original untouched.
+async fn migrate(archive_path: &Path) -> Result<()> {
+    let json = tokio::fs::read_to_string(archive_path).await?;
+    let migrated = model::read_archive(&json)?;
+
+    let directory = archive_path
+        .parent()
+        .ok_or_else(|| "Archive path did not have a parent".to_owned())?;
+    let key = archive_path
+        .file_name()
+        .ok_or_else(|| "Archive path did not have a file name".to_owned())?
+        .to_string_lossy();
+
+    json::write_json(&migrated, &Storage::local(directory), &key).await
+}
+
+/// Re-render an existing JSON archive, without connecting to Discord.
+#[instrument(skip(output_mode, html))]
+async fn render(
+    archive_path: &Path,
+    output_mode: OutputMode,
+    html: HtmlOptions,
+    output_directory: Option<&Path>,
+) -> Result<Vec<String>> {
+    let json = tokio::fs::read_to_string(archive_path).await?;
+    let archive = model::read_archive(&json)?;
+
+    info!(
+        guild = %archive.guild.name,
+        channel = %archive.channel.name,
+        count = %archive.messages.len(),
+        "Read archive"
+    );
+
+    let output_directory = match output_directory {
+        Some(x) => x,
+        None => archive_path
+            .parent()
+            .ok_or_else(|| "Archive path did not have a parent".to_owned())?,
+    };
+
+    // `foo.json` renders to `foo.html`, `foo.dce.json`, etc.
+    let output_file_stem = archive_path
+        .file_stem()
+        .ok_or_else(|| "Archive path did not have a file name".to_owned())?
+        .to_string_lossy();
+
+    // Rendering `foo.json` to JSON in its own directory would replace the archive being read
+    if output_mode.do_json() {
+        let json_path = output_directory.join(format!("{output_file_stem}.json"));
+        if let Ok(json_path) = tokio::fs::canonicalize(&json_path).await {
+            if json_path == tokio::fs::canonicalize(archive_path).await? {
+                return Err(
+                    "Rendering to JSON would overwrite the archive being rendered. \
+                    Use `--output-path` to write to a different directory"
+                        .to_owned()
+                        .into(),
+                );
+            }
+        }
+    }
+
+    let storage = Storage::local(output_directory);
+    let files_created =
+        write_outputs(&archive, output_mode, html, &storage, &output_file_stem).await?;
+    Ok(files_created
+        .iter()
+        .map(|key| storage.location(key))
+        .collect())
+}
+
+/// Report the progress of `command` by editing its response.
+///
+/// The interaction's token expires 15 minutes after the command is used, after which progress is
+/// reported in `fallback`, a message sent to the channel the first time editing the response fails.
+async fn report_command_progress(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    fallback: &tokio::sync::Mutex<Option<Message>>,
+    text: String,
+) {
+    let mut fallback = fallback.lock().await;
+    let result = match &*fallback {
+        Some(message) => message
+            .channel_id
+            .edit_message(ctx, message.id, |builder| builder.content(&text))
+            .await
+            .map(drop),
+        None => match command
+            .edit_original_interaction_response(ctx, |builder| builder.content(&text))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Failed to edit the command response, sending a message instead"
+                );
+                command
+                    .channel_id
+                    .say(ctx, &text)
+                    .await
+                    .map(|message| *fallback = Some(message))
+            }
+        },
+    };
+    if let Err(error) = result {
+        warn!(?error, "Failed to report progress");
+    }
+}
+
+/// Replace the deferred response to `command` with `content`.
+///
+/// The interaction's token expires 15 minutes after the command is used, so the response to a
+/// long archive is posted as a new message in the channel instead if editing fails.
+async fn respond(ctx: &Context, command: &ApplicationCommandInteraction, content: String) {
+    if let Err(error) = command
+        .edit_original_interaction_response(ctx, |builder| builder.content(&content))
+        .await
+    {
+        warn!(
+            ?error,
+            "Failed to edit the command response, sending a message instead"
+        );
+        command
+            .channel_id
+            .say(ctx, format!("<@{}> {}", command.user.id, content))
+            .await
+            .expect(REPLY_FAILURE);
+    }
+}
+
 fn archive_response(
     ArchiveLog {
         download_time,
         render_time,
         files_created,
+        ..
     }: ArchiveLog,
 ) -> String {
-    let download_time = if download_time.as_secs() >= 1 {
-        format!(
-            "{}m{:02}s",
-            download_time.as_secs() / 60,
-            download_time.as_secs() % 60,
-        )
-    } else {
-        format!("{}ms", download_time.as_millis())
-    };
-    let render_time = if render_time.as_secs() >= 1 {
-        format!(
-            "{}m{:02}s",
-            render_time.as_secs() / 60,
-            render_time.as_secs() % 60,
-        )
-    } else {
-        format!("{}ms", render_time.as_millis())
-    };
+    let download_time = format_duration(download_time);
+    let render_time = format_duration(render_time);
     format!(
         indoc! { "
             Archival complete!
@@ -418,17 +1542,32 @@ fn archive_response(
         render_time,
         files_created
             .iter()
-            .map(|x| x.display().to_string())
+            .map(|x| Storage::output().location(x))
             .collect::<Vec<_>>()
             .join("\n")
     )
 }
 
+/// Format a duration as minutes and seconds, or milliseconds if it is less than a second.
+fn format_duration(duration: Duration) -> String {
+    if duration.as_secs() >= 1 {
+        format!(
+            "{}m{:02}s",
+            duration.as_secs() / 60,
+            duration.as_secs() % 60,
+        )
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
 struct Handler;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum OutputMode {
     Json,
+    Dce,
     Html,
     All,
 }
@@ -438,6 +1577,10 @@ impl OutputMode {
         matches!(self, OutputMode::Json | OutputMode::All)
     }
 
+    fn do_dce(self) -> bool {
+        matches!(self, OutputMode::Dce | OutputMode::All)
+    }
+
     fn do_html(self) -> bool {
         matches!(self, OutputMode::Html | OutputMode::All)
     }
@@ -449,6 +1592,7 @@ impl FromStr for OutputMode {
     fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
         match s {
             "json" => Ok(OutputMode::Json),
+            "dce" => Ok(OutputMode::Dce),
             "html" => Ok(OutputMode::Html),
             "all" => Ok(OutputMode::All),
             _ => Err(format!(
@@ -456,6 +1600,7 @@ impl FromStr for OutputMode {
                 Invalid output mode {}. Valid values are one of the following:
                 ```
                 - json
+                - dce
                 - html
                 - all
                 ```"
@@ -477,23 +1622,17 @@ impl EventHandler for Handler {
     // Called when a slash-command is invoked.
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Interaction::ApplicationCommand(command) = interaction {
-            match handle_slash_command(&ctx, &command).await {
-                Ok(x) => {
-                    command
-                        .edit_original_interaction_response(&ctx, |builder| builder.content(x))
-                        .await
-                        .expect(REPLY_FAILURE);
+            let response = match handle_slash_command(&ctx, &command).await {
+                Ok(x) => x,
+                Err(e @ (Error::Forbidden(_) | Error::Download(_) | Error::Cancelled)) => {
+                    e.to_string()
                 }
                 Err(e) => {
                     error!(error = ?e, "An error occurred in handle_slash_command()");
-                    command
-                        .edit_original_interaction_response(&ctx, |builder| {
-                            builder.content(format!("Error:\n```\n{:?}\n```", e))
-                        })
-                        .await
-                        .expect(REPLY_FAILURE);
+                    format!("Error:\n```\n{:?}\n```", e)
                 }
-            }
+            };
+            respond(&ctx, &command, response).await;
         }
     }
 
@@ -502,51 +1641,71 @@ impl EventHandler for Handler {
     // If that message starts with `!archive`, attempt to parse that as an archive command (emoji
     // or channel.)
     async fn message(&self, ctx: Context, msg: Message) {
+        watch::record_create(&msg).await;
+
         if msg.content.starts_with("!archive") {
-            if let Err(e) = handle_archive_message(&ctx, &msg).await {
-                error!(error = ?e, "An error occurred in handle_archive_message()");
-                msg.reply(&ctx, format!("Error:\n```\n{:?}\n```", e))
-                    .await
-                    .expect(REPLY_FAILURE);
+            match handle_archive_message(&ctx, &msg).await {
+                Ok(()) => {}
+                Err(e @ (Error::Forbidden(_) | Error::Download(_) | Error::Cancelled)) => {
+                    msg.reply(&ctx, e.to_string()).await.expect(REPLY_FAILURE);
+                }
+                Err(e) => {
+                    error!(error = ?e, "An error occurred in handle_archive_message()");
+                    msg.reply(&ctx, format!("Error:\n```\n{:?}\n```", e))
+                        .await
+                        .expect(REPLY_FAILURE);
+                }
             }
         }
     }
 
+    // Called when a message is edited, or Discord adds link previews to it.
+    async fn message_update(
+        &self,
+        _ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        watch::record_update(&event).await;
+    }
+
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        watch::record_delete(channel_id, &[deleted_message_id]).await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        watch::record_delete(channel_id, &multiple_deleted_messages_ids).await;
+    }
+
     // Called when the bot is ready.
     //
     // Register slash commands.
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!(name = %ready.user.name, num_guilds = %ready.guilds.len(), "Bot logged in");
 
+        // Commands registered globally are shown to the same users in every guild, so if any guild
+        // requires different permissions they are registered for each guild in `guild_create`
+        // instead
+        let per_guild = config::get().has_per_guild_permissions();
         let commands = Command::set_global_application_commands(&ctx, |builder| {
-            builder
-                .create_application_command(|command_builder| {
-                    command_builder
-                        .name("archive_emoji")
-                        .description("Archive the emoji from the current server")
-                })
-                .create_application_command(|command_builder| {
-                    command_builder
-                        .name("archive")
-                        .description("Archive the contents of a channel")
-                        .create_option(|option_builder| {
-                            option_builder
-                                .name("channel")
-                                .description("The channel to archive")
-                                .kind(CommandOptionType::Channel)
-                                .required(true)
-                        })
-                        .create_option(|option_builder| {
-                            option_builder
-                                .name("output_format")
-                                .description("The file format to output to")
-                                .kind(CommandOptionType::String)
-                                .add_string_choice("JSON", "json")
-                                .add_string_choice("HTML", "html")
-                                .add_string_choice("all", "all")
-                                .required(true)
-                        })
-                })
+            if per_guild {
+                builder
+            } else {
+                create_commands(builder, config::get().defaults().required_permissions)
+            }
         })
         .await
         .unwrap();
@@ -558,18 +1717,387 @@ impl EventHandler for Handler {
                 .collect::<Vec<_>>(),
             "Registered slash commands",
         );
+
+        schedule::start(ctx);
+    }
+
+    // Called when the bot joins a guild, or when a guild becomes available on startup.
+    //
+    // Register slash commands for the guild if they aren't registered globally, or remove any left
+    // over from when they were.
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        let config = config::get();
+        let register = config.has_per_guild_permissions() && config.is_guild_allowed(guild.id);
+        let required_permissions = config.guild(guild.id).required_permissions;
+        match guild
+            .id
+            .set_application_commands(&ctx, |builder| {
+                if register {
+                    create_commands(builder, required_permissions)
+                } else {
+                    builder
+                }
+            })
+            .await
+        {
+            Ok(commands) => info!(
+                guild = %guild.name,
+                count = %commands.len(),
+                "Registered slash commands in guild"
+            ),
+            Err(error) => error!(?error, guild = %guild.name, "Failed to register slash commands"),
+        }
     }
 }
 
+/// Add the bot's slash commands to `builder`, shown by default to users with
+/// `required_permissions`.
+fn create_commands(
+    builder: &mut CreateApplicationCommands,
+    required_permissions: Permissions,
+) -> &mut CreateApplicationCommands {
+    builder
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive_emoji")
+                .description("Archive the emoji from the current server")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive")
+                .description("Archive the contents of a channel")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("channel")
+                        .description("The channel to archive")
+                        .kind(CommandOptionType::Channel)
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("output_format")
+                        .description("The file format to output to")
+                        .kind(CommandOptionType::String)
+                        .add_string_choice("JSON", "json")
+                        .add_string_choice("DiscordChatExporter JSON", "dce")
+                        .add_string_choice("HTML", "html")
+                        .add_string_choice("all", "all")
+                        .required(false)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("upload")
+                        .description(
+                            "Attach the archive to the response, as well as saving it \
+                                on the bot's host",
+                        )
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("pinned_only")
+                        .description("Only archive the channel's pinned messages")
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("schedule_archive")
+                .description("Archive a channel on a recurring schedule")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("channel")
+                        .description("The channel to archive")
+                        .kind(CommandOptionType::Channel)
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("output_format")
+                        .description("The file format to output to")
+                        .kind(CommandOptionType::String)
+                        .add_string_choice("JSON", "json")
+                        .add_string_choice("DiscordChatExporter JSON", "dce")
+                        .add_string_choice("HTML", "html")
+                        .add_string_choice("all", "all")
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("day")
+                        .description("The day of the week to archive on")
+                        .kind(CommandOptionType::String)
+                        .add_string_choice("Every day", "every_day")
+                        .add_string_choice("Monday", "monday")
+                        .add_string_choice("Tuesday", "tuesday")
+                        .add_string_choice("Wednesday", "wednesday")
+                        .add_string_choice("Thursday", "thursday")
+                        .add_string_choice("Friday", "friday")
+                        .add_string_choice("Saturday", "saturday")
+                        .add_string_choice("Sunday", "sunday")
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("time")
+                        .description(
+                            "The time of day to archive at, as HH:MM in UTC (not the server's \
+                            timezone)",
+                        )
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("log_channel")
+                        .description(
+                            "The channel to post a summary to after each archive. \
+                                Defaults to this channel",
+                        )
+                        .kind(CommandOptionType::Channel)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("unschedule_archive")
+                .description("Stop a recurring archive")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("id")
+                        .description("The ID of the scheduled archive")
+                        .kind(CommandOptionType::Integer)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("scheduled_archives")
+                .description("List the recurring archives in this server")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("watch")
+                .description(
+                    "Record the messages sent, edited and deleted in a channel from now on",
+                )
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("channel")
+                        .description("The channel to watch")
+                        .kind(CommandOptionType::Channel)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("unwatch")
+                .description("Stop recording a watched channel")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("channel")
+                        .description("The channel to stop watching")
+                        .kind(CommandOptionType::Channel)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("watched_channels")
+                .description("List the channels being watched in this server")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive_status")
+                .description("List the archive jobs queued or running in this server")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive_cancel")
+                .description("Cancel a queued or running archive job")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("id")
+                        .description("The ID of the archive job")
+                        .kind(CommandOptionType::Integer)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive_structure")
+                .description("Back up this server's roles, channels, permissions and settings")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions)
+                .name("archive_members")
+                .description("Save a list of everyone in this server as JSON and CSV")
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions | Permissions::VIEW_AUDIT_LOG)
+                .name("archive_audit_log")
+                .description(
+                    "Add this server's audit log to its archive, as JSON and an HTML \
+                        timeline",
+                )
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(
+                    required_permissions | Permissions::MANAGE_ROLES | Permissions::MANAGE_CHANNELS,
+                )
+                .name("restore_structure")
+                .description(
+                    "Recreate the roles and channels from a structure backup in this server",
+                )
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("archive")
+                        .description("The name of the directory created by /archive_structure")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("dry_run")
+                        .description("Only list the changes that would be made (default: true)")
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(
+                    required_permissions | Permissions::MANAGE_EMOJIS_AND_STICKERS,
+                )
+                .name("restore_emoji")
+                .description("Upload the emoji and stickers from an emoji archive to this server")
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("archive")
+                        .description("The name of the directory created by /archive_emoji")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command_builder| {
+            command_builder
+                .default_member_permissions(required_permissions | Permissions::MANAGE_WEBHOOKS)
+                .name("replay_archive")
+                .description(
+                    "Repost the messages in a JSON archive into a channel through a webhook",
+                )
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("archive")
+                        .description(
+                            "The file name of the JSON archive, such as server-channel.json",
+                        )
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|option_builder| {
+                    option_builder
+                        .name("channel")
+                        .description("The channel to post the messages in")
+                        .kind(CommandOptionType::Channel)
+                        .required(true)
+                })
+        })
+}
+
 /// A small discord bot to archive the messages in a discord text channel.
 #[derive(Parser, Debug)]
 #[clap(name = "discord-channel-archiver", version, author, about)]
 struct Opt {
-    /// File containing the token
-    token_filename: PathBuf,
-    /// File containing the application id
-    appid_filename: PathBuf,
-    /// The path to output files to
-    #[clap(default_value = "/dev/shm")]
-    output_path: PathBuf,
+    #[clap(subcommand)]
+    command: Subcommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Run the bot, archiving channels when asked to through commands
+    Bot {
+        /// File containing the token
+        token_filename: Option<PathBuf>,
+        /// File containing the application id
+        appid_filename: Option<PathBuf>,
+        /// The path to output files to [default: /dev/shm]
+        output_path: Option<PathBuf>,
+        /// The permissions a user needs to use the bot's commands, as a comma-separated list of
+        /// permission names. To archive a channel, they need these permissions in that channel,
+        /// along with permission to read its history [default: MANAGE_MESSAGES]
+        #[clap(long, value_parser = permissions::parse_permissions)]
+        required_permissions: Option<Permissions>,
+        /// The maximum number of archives that can run at once. Further archives are queued
+        /// [default: 2]
+        #[clap(long)]
+        max_jobs: Option<usize>,
+        /// The maximum number of archives that can run at once in a single guild [default: 1]
+        #[clap(long)]
+        max_jobs_per_guild: Option<usize>,
+        /// The file format(s) to output to when a command doesn't say [default: all]
+        #[clap(long)]
+        default_format: Option<OutputMode>,
+        #[clap(flatten)]
+        config: ConfigOpt,
+    },
+    /// Archive a single channel and exit, without running the bot
+    Archive {
+        /// File containing the token
+        token_filename: Option<PathBuf>,
+        /// The ID of the guild containing the channel
+        #[clap(long)]
+        guild: u64,
+        /// The ID of the channel to archive
+        #[clap(long)]
+        channel: u64,
+        /// The file format(s) to output to [default: all]
+        #[clap(long, short)]
+        format: Option<OutputMode>,
+        #[clap(flatten)]
+        config: ConfigOpt,
+        /// The path to output files to [default: /dev/shm]
+        output_path: Option<PathBuf>,
+    },
+    /// Render an existing JSON archive to other formats, without connecting to Discord
+    Render {
+        /// An archive written by the `json` output mode
+        archive: PathBuf,
+        /// The file format(s) to output to
+        #[clap(long, short, default_value = "html")]
+        format: OutputMode,
+        /// The path to output files to. Defaults to the directory containing the archive
+        #[clap(long, short)]
+        output_path: Option<PathBuf>,
+        /// The colour scheme of HTML output (`dark` or `light`)
+        #[clap(long, default_value = "dark")]
+        theme: Theme,
+        /// The timezone timestamps are shown in in HTML output, e.g. `Europe/London`
+        #[clap(long, default_value = "UTC")]
+        timezone: Tz,
+    },
+    /// Print the JSON Schema of the archive format written by the `json` output mode
+    Schema,
+    /// Upgrade an archive written by an older version of the `json` output mode to the current
+    /// format, in place
+    Migrate {
+        /// An archive written by the `json` output mode
+        archive: PathBuf,
+    },
 }
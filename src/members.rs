@@ -0,0 +1,155 @@
+use crate::model;
+use crate::model::ArchivedMember;
+use crate::model::ArchivedRole;
+use crate::naming;
+use crate::storage::Storage;
+use crate::Result;
+
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::model::guild::Guild;
+use tracing::*;
+
+/// The version of the format of [`MemberList`].
+const MEMBER_LIST_VERSION: u32 = 1;
+
+/// The columns of the CSV member list.
+const CSV_HEADER: &[&str] = &[
+    "id",
+    "username",
+    "discriminator",
+    "nick",
+    "roles",
+    "joined_at",
+    "premium_since",
+    "avatar_url",
+    "bot",
+];
+
+/// Everyone in a guild, as written by [`archive_members`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemberList {
+    pub schema_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub guild_id: u64,
+    pub guild_name: String,
+    /// The roles of the guild, so that the role IDs of each member can be looked up.
+    pub roles: Vec<ArchivedRole>,
+    pub members: Vec<ArchivedMember>,
+}
+
+/// What [`archive_members`] saved.
+pub struct MemberArchiveLog {
+    pub members: usize,
+    /// Where the JSON and CSV files were saved, for showing to the user.
+    pub locations: Vec<String>,
+}
+
+/// Fetch every member of `guild` and save them as JSON and CSV files in [`Storage::output`].
+#[instrument(skip_all, fields(guild = %guild.name))]
+pub async fn archive_members(http: &Http, guild: &Guild) -> Result<MemberArchiveLog> {
+    info!("Starting member archive");
+    let storage = Storage::output();
+    let exported_at = Utc::now();
+    let stem = format!(
+        "{}-members-{}",
+        naming::sanitize(&guild.name.replace(char::is_whitespace, "-").to_lowercase()),
+        exported_at.format("%Y-%m-%dT%H-%M-%S")
+    );
+
+    let mut members = model::fetch_members(http, guild.id).await?;
+    members.sort_by_key(|member| member.user.id);
+
+    let mut roles = guild.roles.values().collect::<Vec<_>>();
+    roles.sort_by_key(|role| (std::cmp::Reverse(role.position), role.id));
+
+    let list = MemberList {
+        schema_version: MEMBER_LIST_VERSION,
+        exported_at,
+        guild_id: guild.id.0,
+        guild_name: guild.name.clone(),
+        roles: roles.into_iter().map(ArchivedRole::from).collect(),
+        members,
+    };
+
+    let json_key = format!("{}.json", stem);
+    let csv_key = format!("{}.csv", stem);
+    storage
+        .write(&json_key, serde_json::to_string_pretty(&list)?)
+        .await?;
+    storage.write(&csv_key, to_csv(&list)).await?;
+
+    info!(count = %list.members.len(), "Member archive complete");
+
+    Ok(MemberArchiveLog {
+        members: list.members.len(),
+        locations: vec![storage.location(&json_key), storage.location(&csv_key)],
+    })
+}
+
+/// `list` as CSV, with one row per member. Roles are listed by name, separated by `;`.
+fn to_csv(list: &MemberList) -> String {
+    let role_names = list
+        .roles
+        .iter()
+        .map(|role| (role.id, role.name.as_str()))
+        .collect::<HashMap<_, _>>();
+
+    let mut csv = CSV_HEADER.join(",") + "\r\n";
+    for member in &list.members {
+        let roles = member
+            .roles
+            .iter()
+            .map(|id| {
+                role_names
+                    .get(id)
+                    .map_or_else(|| id.to_string(), |x| x.to_string())
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        let row = [
+            member.user.id.to_string(),
+            member.user.name.clone(),
+            format!("{:04}", member.user.discriminator),
+            member.nick.clone().unwrap_or_default(),
+            roles,
+            member.joined_at.map(|x| x.to_rfc3339()).unwrap_or_default(),
+            member
+                .premium_since
+                .map(|x| x.to_rfc3339())
+                .unwrap_or_default(),
+            member
+                .avatar_url
+                .clone()
+                .unwrap_or_else(|| member.user.avatar_url.clone()),
+            member.user.bot.to_string(),
+        ];
+        csv += &row
+            .iter()
+            .map(|x| csv_field(x))
+            .collect::<Vec<_>>()
+            .join(",");
+        csv += "\r\n";
+    }
+    csv
+}
+
+/// Quote `field` for CSV if it needs it.
+///
+/// Fields starting with a character that spreadsheets treat as a formula are prefixed with `'`,
+/// since names and nicknames are chosen by members.
+fn csv_field(field: &str) -> String {
+    let field = match field.starts_with(['=', '+', '-', '@']) {
+        true => format!("'{}", field),
+        false => field.to_owned(),
+    };
+    match field.contains([',', '"', '\r', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field,
+    }
+}
@@ -0,0 +1,694 @@
+use crate::Result;
+
+use std::collections::BTreeMap;
+
+use chrono::DateTime;
+use chrono::Utc;
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::http::StatusCode;
+use serenity::model::channel::Attachment;
+use serenity::model::channel::Embed;
+use serenity::model::channel::GuildChannel;
+use serenity::model::channel::Message;
+use serenity::model::channel::MessageReaction;
+use serenity::model::channel::ReactionType;
+use serenity::model::guild::Guild;
+use serenity::model::guild::Member;
+use serenity::model::guild::PartialGuild;
+use serenity::model::guild::Role;
+use serenity::model::id::GuildId;
+use serenity::model::id::UserId;
+use serenity::model::sticker::StickerFormatType;
+use serenity::model::sticker::StickerItem;
+use serenity::model::user::User;
+use serenity::model::Timestamp;
+use tracing::*;
+
+/// The version of the archive format written by this version of the archiver.
+///
+/// This must be incremented whenever a change is made to the structs in this module that would
+/// prevent an older version of the archiver from reading the output, and a migration from the
+/// previous version added to [`read_archive`].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A channel archive, as written by `write_json`.
+///
+/// This is deliberately independent of serenity's models, so that upgrading serenity does not
+/// silently change the format of our archives.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Archive {
+    pub schema_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub guild: ArchivedGuild,
+    pub channel: ArchivedChannel,
+    /// Every channel in the guild, used to resolve channel mentions.
+    pub channels: Vec<ChannelSummary>,
+    pub members: Vec<ArchivedMember>,
+    /// Users referenced by the archive (authors and mentions), including those that are no longer
+    /// members of the guild.
+    pub users: Vec<ArchivedUser>,
+    pub messages: Vec<ArchivedMessage>,
+    /// Whether the archive only has the channel's pinned messages, rather than all of them.
+    #[serde(default)]
+    pub pinned_only: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedGuild {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+    pub icon_url: Option<String>,
+    pub roles: Vec<ArchivedRole>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedRole {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+    /// The role colour as `0xRRGGBB`, where `0` means the role has no colour.
+    pub colour: u32,
+    pub position: i64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedChannel {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+    /// Discord's numeric channel type.
+    pub kind: u8,
+    pub topic: Option<String>,
+    #[serde(with = "snowflake::option")]
+    #[schemars(with = "Option<String>")]
+    pub category_id: Option<u64>,
+    pub category_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ChannelSummary {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedUser {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+    pub discriminator: u16,
+    pub bot: bool,
+    pub avatar_url: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedMember {
+    pub user: ArchivedUser,
+    pub nick: Option<String>,
+    #[serde(with = "snowflake::vec")]
+    #[schemars(with = "Vec<String>")]
+    pub roles: Vec<u64>,
+    pub joined_at: Option<DateTime<Utc>>,
+    /// When the member started boosting the guild, if they are boosting it.
+    #[serde(default)]
+    pub premium_since: Option<DateTime<Utc>>,
+    /// The member's avatar in this guild, if they have set one different from their user avatar.
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedMessage {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    /// Discord's numeric message type.
+    pub kind: u8,
+    pub author: ArchivedUser,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    pub edited_timestamp: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    pub attachments: Vec<ArchivedAttachment>,
+    pub embeds: Vec<ArchivedEmbed>,
+    pub reactions: Vec<ArchivedReaction>,
+    pub stickers: Vec<ArchivedSticker>,
+    /// The IDs of the users mentioned in this message. Details of each are in [`Archive::users`].
+    #[serde(with = "snowflake::vec")]
+    #[schemars(with = "Vec<String>")]
+    pub mentions: Vec<u64>,
+    pub reference: Option<ArchivedReference>,
+    /// Earlier versions of the message's content, oldest first, as recorded by watching the
+    /// channel. Empty if the message was never edited while the channel was watched.
+    #[serde(default)]
+    pub revisions: Vec<ArchivedRevision>,
+    /// When the message was deleted, if it was deleted while the channel was watched.
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// A version of a message's content that was later edited.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedRevision {
+    pub content: String,
+    /// When this version was written: when the message was sent, for the first version, and when
+    /// it was edited otherwise.
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedAttachment {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub filename: String,
+    pub url: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedEmbed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub timestamp: Option<String>,
+    pub colour: Option<u32>,
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    pub author_icon_url: Option<String>,
+    pub image_url: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub fields: Vec<ArchivedEmbedField>,
+    pub footer_text: Option<String>,
+    pub footer_icon_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedEmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedReaction {
+    /// The ID of a custom emoji. `None` for unicode emoji.
+    #[serde(with = "snowflake::option")]
+    #[schemars(with = "Option<String>")]
+    pub emoji_id: Option<u64>,
+    /// The name of a custom emoji, or the emoji itself for unicode emoji.
+    pub emoji_name: String,
+    pub animated: bool,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedSticker {
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub id: u64,
+    pub name: String,
+    /// One of `png`, `apng`, `lottie` or `unknown`.
+    pub format: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ArchivedReference {
+    #[serde(with = "snowflake::option")]
+    #[schemars(with = "Option<String>")]
+    pub message_id: Option<u64>,
+    #[serde(with = "snowflake")]
+    #[schemars(with = "String")]
+    pub channel_id: u64,
+    #[serde(with = "snowflake::option")]
+    #[schemars(with = "Option<String>")]
+    pub guild_id: Option<u64>,
+}
+
+impl Archive {
+    /// Build an archive of `messages`, fetching the members and channels of the guild needed to
+    /// render it later without access to Discord.
+    ///
+    /// If `pinned_only`, `messages` are the channel's pins, and only the members who wrote or are
+    /// mentioned in them are fetched.
+    #[instrument(skip_all)]
+    pub async fn collect(
+        http: &Http,
+        guild: &ArchivedGuild,
+        channel: &GuildChannel,
+        messages: &[Message],
+        pinned_only: bool,
+    ) -> Result<Self> {
+        trace!("Collecting archive");
+
+        let guild_id = GuildId(guild.id);
+
+        let channels = guild_id.channels(http).await?;
+
+        let category_name = channel
+            .parent_id
+            .and_then(|id| channels.get(&id))
+            .map(|category| category.name.clone());
+
+        let mut users = BTreeMap::new();
+        for message in messages {
+            users
+                .entry(message.author.id)
+                .or_insert_with(|| ArchivedUser::from(&message.author));
+            for user in &message.mentions {
+                users
+                    .entry(user.id)
+                    .or_insert_with(|| ArchivedUser::from(user));
+            }
+        }
+
+        // A digest of a channel's pins only needs the members who appear in it, which is far fewer
+        // requests than paging through a large guild
+        let members = if pinned_only {
+            fetch_members_among(http, guild_id, users.keys().copied()).await?
+        } else {
+            fetch_members(http, guild_id).await?
+        };
+
+        Ok(Self {
+            schema_version: SCHEMA_VERSION,
+            exported_at: Utc::now(),
+            guild: guild.clone(),
+            channel: ArchivedChannel {
+                category_name,
+                ..ArchivedChannel::from(channel)
+            },
+            channels: channels
+                .values()
+                .map(|channel| ChannelSummary {
+                    id: channel.id.0,
+                    name: channel.name.clone(),
+                })
+                .collect(),
+            members,
+            users: users.into_values().collect(),
+            messages: messages.iter().map(ArchivedMessage::from).collect(),
+            pinned_only,
+        })
+    }
+}
+
+/// Fetch every member of `guild_id`.
+///
+/// The cache only has the members the gateway has told us about, and a single request returns at
+/// most 1000, so this pages through all of them.
+pub async fn fetch_members(http: &Http, guild_id: GuildId) -> Result<Vec<ArchivedMember>> {
+    let mut members = Vec::new();
+    let mut stream = Box::pin(guild_id.members_iter(http));
+    while let Some(member) = stream.next().await {
+        members.push(ArchivedMember::from(&member?));
+    }
+    Ok(members)
+}
+
+/// Fetch the members of `guild_id` among `users`, skipping those who are not in the guild.
+async fn fetch_members_among(
+    http: &Http,
+    guild_id: GuildId,
+    users: impl IntoIterator<Item = UserId>,
+) -> Result<Vec<ArchivedMember>> {
+    let mut members = Vec::new();
+    for user_id in users {
+        match guild_id.member(http, user_id).await {
+            Ok(member) => members.push(ArchivedMember::from(&member)),
+            Err(serenity::Error::Http(e)) if e.status_code() == Some(StatusCode::NOT_FOUND) => {
+                trace!(user = %user_id, "User is no longer a member");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(members)
+}
+
+/// Read an archive written by any version of `write_json`, migrating it to the current
+/// [`SCHEMA_VERSION`].
+pub fn read_archive(json: &str) -> Result<Archive> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+
+    // Archives written before the format was versioned have no `schema_version` field.
+    let version = match value.get("schema_version") {
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| "Archive has an invalid `schema_version`".to_owned())?,
+        None => 0,
+    };
+
+    trace!(%version, "Reading archive");
+
+    match version {
+        0 => migrate_v0(value),
+        1 => Ok(serde_json::from_value(value)?),
+        _ => Err(format!(
+            "Archive has schema version {}, but this version of the archiver only supports up to \
+            version {}",
+            version, SCHEMA_VERSION,
+        )
+        .into()),
+    }
+}
+
+/// Version 0 archives are a direct serialization of serenity's `Guild`, `GuildChannel` and
+/// `Message`.
+fn migrate_v0(value: serde_json::Value) -> Result<Archive> {
+    #[derive(Deserialize)]
+    struct V0 {
+        guild: Guild,
+        channel: GuildChannel,
+        messages: Vec<Message>,
+    }
+
+    let V0 {
+        guild,
+        channel,
+        messages,
+    } = serde_json::from_value(value)?;
+
+    let mut users = BTreeMap::new();
+    for message in &messages {
+        users
+            .entry(message.author.id)
+            .or_insert_with(|| ArchivedUser::from(&message.author));
+        for user in &message.mentions {
+            users
+                .entry(user.id)
+                .or_insert_with(|| ArchivedUser::from(user));
+        }
+    }
+
+    let category_name = channel
+        .parent_id
+        .and_then(|id| guild.channels.get(&id))
+        .and_then(|category| category.clone().category())
+        .map(|category| category.name);
+
+    Ok(Archive {
+        schema_version: SCHEMA_VERSION,
+        exported_at: messages
+            .iter()
+            .map(|message| timestamp(message.timestamp))
+            .max()
+            .unwrap_or_else(Utc::now),
+        channels: guild
+            .channels
+            .iter()
+            .filter_map(|(id, channel)| {
+                let name = match channel {
+                    serenity::model::channel::Channel::Guild(c) => c.name.clone(),
+                    serenity::model::channel::Channel::Category(c) => c.name.clone(),
+                    _ => return None,
+                };
+                Some(ChannelSummary { id: id.0, name })
+            })
+            .collect(),
+        members: guild.members.values().map(ArchivedMember::from).collect(),
+        guild: ArchivedGuild::from(&guild),
+        channel: ArchivedChannel {
+            category_name,
+            ..ArchivedChannel::from(&channel)
+        },
+        users: users.into_values().collect(),
+        messages: messages.iter().map(ArchivedMessage::from).collect(),
+        pinned_only: false,
+    })
+}
+
+/// The JSON Schema describing the current archive format.
+pub fn json_schema() -> String {
+    let schema = schemars::schema_for!(Archive);
+    serde_json::to_string_pretty(&schema).expect("Schema should always serialize")
+}
+
+impl From<&Guild> for ArchivedGuild {
+    fn from(guild: &Guild) -> Self {
+        Self {
+            id: guild.id.0,
+            name: guild.name.clone(),
+            icon_url: guild.icon_url(),
+            roles: guild.roles.values().map(ArchivedRole::from).collect(),
+        }
+    }
+}
+
+impl From<&PartialGuild> for ArchivedGuild {
+    fn from(guild: &PartialGuild) -> Self {
+        Self {
+            id: guild.id.0,
+            name: guild.name.clone(),
+            icon_url: guild.icon_url(),
+            roles: guild.roles.values().map(ArchivedRole::from).collect(),
+        }
+    }
+}
+
+impl From<&Role> for ArchivedRole {
+    fn from(role: &Role) -> Self {
+        Self {
+            id: role.id.0,
+            name: role.name.clone(),
+            colour: role.colour.0,
+            position: role.position,
+        }
+    }
+}
+
+impl From<&GuildChannel> for ArchivedChannel {
+    fn from(channel: &GuildChannel) -> Self {
+        Self {
+            id: channel.id.0,
+            name: channel.name.clone(),
+            kind: channel.kind.num() as u8,
+            topic: channel.topic.clone(),
+            category_id: channel.parent_id.map(|x| x.0),
+            category_name: None,
+        }
+    }
+}
+
+impl From<&User> for ArchivedUser {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id.0,
+            name: user.name.clone(),
+            discriminator: user.discriminator,
+            bot: user.bot,
+            avatar_url: user.face(),
+        }
+    }
+}
+
+impl From<&Member> for ArchivedMember {
+    fn from(member: &Member) -> Self {
+        Self {
+            user: ArchivedUser::from(&member.user),
+            nick: member.nick.clone(),
+            roles: member.roles.iter().map(|x| x.0).collect(),
+            joined_at: member.joined_at.map(timestamp),
+            premium_since: member.premium_since.map(timestamp),
+            avatar_url: member.avatar_url(),
+        }
+    }
+}
+
+impl From<&Message> for ArchivedMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            id: message.id.0,
+            kind: message.kind.num() as u8,
+            author: ArchivedUser::from(&message.author),
+            content: message.content.clone(),
+            timestamp: timestamp(message.timestamp),
+            edited_timestamp: message.edited_timestamp.map(timestamp),
+            pinned: message.pinned,
+            attachments: message
+                .attachments
+                .iter()
+                .map(ArchivedAttachment::from)
+                .collect(),
+            embeds: message.embeds.iter().map(ArchivedEmbed::from).collect(),
+            reactions: message
+                .reactions
+                .iter()
+                .map(ArchivedReaction::from)
+                .collect(),
+            stickers: message
+                .sticker_items
+                .iter()
+                .map(ArchivedSticker::from)
+                .collect(),
+            mentions: message.mentions.iter().map(|x| x.id.0).collect(),
+            reference: message
+                .message_reference
+                .as_ref()
+                .map(|r| ArchivedReference {
+                    message_id: r.message_id.map(|x| x.0),
+                    channel_id: r.channel_id.0,
+                    guild_id: r.guild_id.map(|x| x.0),
+                }),
+            revisions: Vec::new(),
+            deleted_at: None,
+        }
+    }
+}
+
+impl From<&Attachment> for ArchivedAttachment {
+    fn from(attachment: &Attachment) -> Self {
+        Self {
+            id: attachment.id.0,
+            filename: attachment.filename.clone(),
+            url: attachment.url.clone(),
+            size: attachment.size,
+            content_type: attachment.content_type.clone(),
+        }
+    }
+}
+
+impl From<&Embed> for ArchivedEmbed {
+    fn from(embed: &Embed) -> Self {
+        Self {
+            title: embed.title.clone(),
+            description: embed.description.clone(),
+            url: embed.url.clone(),
+            timestamp: embed.timestamp.clone(),
+            colour: embed.colour.map(|x| x.0),
+            author_name: embed.author.as_ref().map(|x| x.name.clone()),
+            author_url: embed.author.as_ref().and_then(|x| x.url.clone()),
+            author_icon_url: embed.author.as_ref().and_then(|x| x.icon_url.clone()),
+            image_url: embed.image.as_ref().map(|x| x.url.clone()),
+            thumbnail_url: embed.thumbnail.as_ref().map(|x| x.url.clone()),
+            fields: embed
+                .fields
+                .iter()
+                .map(|x| ArchivedEmbedField {
+                    name: x.name.clone(),
+                    value: x.value.clone(),
+                    inline: x.inline,
+                })
+                .collect(),
+            footer_text: embed.footer.as_ref().map(|x| x.text.clone()),
+            footer_icon_url: embed.footer.as_ref().and_then(|x| x.icon_url.clone()),
+        }
+    }
+}
+
+impl From<&MessageReaction> for ArchivedReaction {
+    fn from(reaction: &MessageReaction) -> Self {
+        let (emoji_id, emoji_name, animated) = match &reaction.reaction_type {
+            ReactionType::Custom { animated, id, name } => {
+                (Some(id.0), name.clone().unwrap_or_default(), *animated)
+            }
+            ReactionType::Unicode(s) => (None, s.clone(), false),
+            _ => (None, String::new(), false),
+        };
+        Self {
+            emoji_id,
+            emoji_name,
+            animated,
+            count: reaction.count,
+        }
+    }
+}
+
+impl From<&StickerItem> for ArchivedSticker {
+    fn from(sticker: &StickerItem) -> Self {
+        Self {
+            id: sticker.id.0,
+            name: sticker.name.clone(),
+            format: match sticker.format_type {
+                StickerFormatType::Png => "png",
+                StickerFormatType::Apng => "apng",
+                StickerFormatType::Lottie => "lottie",
+                _ => "unknown",
+            }
+            .to_owned(),
+        }
+    }
+}
+
+pub fn timestamp(ts: Timestamp) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&ts.to_string())
+        .expect("Serenity timestamps should be valid RFC 3339")
+        .with_timezone(&Utc)
+}
+
+/// Discord IDs are serialized as strings, as they do not fit in the integers JavaScript can
+/// represent exactly.
+pub(crate) mod snowflake {
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+
+    pub mod option {
+        use serde::Deserialize;
+        use serde::Deserializer;
+        use serde::Serializer;
+
+        pub fn serialize<S: Serializer>(
+            id: &Option<u64>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match id {
+                Some(id) => serializer.collect_str(id),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<u64>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|s| s.parse().map_err(serde::de::Error::custom))
+                .transpose()
+        }
+    }
+
+    pub mod vec {
+        use serde::ser::SerializeSeq;
+        use serde::Deserialize;
+        use serde::Deserializer;
+        use serde::Serializer;
+
+        pub fn serialize<S: Serializer>(ids: &[u64], serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(ids.len()))?;
+            for id in ids {
+                seq.serialize_element(&id.to_string())?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<u64>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .into_iter()
+                .map(|s| s.parse().map_err(serde::de::Error::custom))
+                .collect()
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::model::Archive;
+use crate::storage::Storage;
+use crate::Result;
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use chrono::Utc;
+use indoc::indoc;
+use once_cell::sync::Lazy;
+use tracing::*;
+
+/// The template used if none is configured.
+pub const DEFAULT_TEMPLATE: &str = "{guild}-{channel}";
+
+/// The longest a sanitised name can be, in bytes. This leaves room below the usual 255 byte limit
+/// on file names for a `-N` suffix, an extension, and the temporary names used while writing.
+const MAX_NAME_LENGTH: usize = 180;
+
+/// Every extension an archive can be written with. A name is only used if no file with any of
+/// these extensions exists, so that the files from one archive always share a name.
+const EXTENSIONS: &[&str] = &["json", "dce.json", "html", "zip", "tar.zst"];
+
+/// Names that archives currently being written have claimed, but may not have created yet.
+static CLAIMED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Guild,
+    GuildId,
+    Channel,
+    ChannelId,
+    Timestamp,
+    Date,
+    First,
+    Last,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A template for the names archives are written under, without an extension, e.g.
+/// `{guild}-{channel}-{date}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE
+            .parse()
+            .expect("The default template is valid")
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed `{{` in filename template `{}`", s))?;
+            let name = &rest[start + 1..start + end];
+            let field = match name {
+                "guild" => Field::Guild,
+                "guild_id" => Field::GuildId,
+                "channel" => Field::Channel,
+                "channel_id" => Field::ChannelId,
+                "timestamp" => Field::Timestamp,
+                "date" => Field::Date,
+                "first" => Field::First,
+                "last" => Field::Last,
+                _ => {
+                    return Err(format!(
+                        indoc! { "
+                        Unknown placeholder `{{{}}}` in filename template. Valid placeholders are:
+                        - {{guild}}, {{guild_id}}
+                        - {{channel}}, {{channel_id}}
+                        - {{timestamp}}, {{date}} (when the archive was made)
+                        - {{first}}, {{last}} (the dates of the oldest and newest messages)"
+                        },
+                        name
+                    ))
+                }
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched `}}` in filename template `{}`", s));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        if parts.is_empty() {
+            return Err("The filename template must not be empty".to_owned());
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl FilenameTemplate {
+    /// The name `archive` should be written under, sanitised so that it is a single path
+    /// component.
+    pub fn render(&self, archive: &Archive) -> String {
+        let date = |x: DateTime<Utc>| x.format("%Y-%m-%d").to_string();
+        // An empty channel's range is the time it was archived
+        let timestamps = archive.messages.iter().map(|x| x.timestamp);
+        let first = timestamps.clone().min().unwrap_or(archive.exported_at);
+        let last = timestamps.max().unwrap_or(archive.exported_at);
+
+        let name = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(x) => x.clone(),
+                Part::Field(Field::Guild) => archive.guild.name.clone(),
+                Part::Field(Field::GuildId) => archive.guild.id.to_string(),
+                Part::Field(Field::Channel) => archive.channel.name.clone(),
+                Part::Field(Field::ChannelId) => archive.channel.id.to_string(),
+                Part::Field(Field::Timestamp) => {
+                    archive.exported_at.format("%Y-%m-%dT%H-%M-%S").to_string()
+                }
+                Part::Field(Field::Date) => date(archive.exported_at),
+                Part::Field(Field::First) => date(first),
+                Part::Field(Field::Last) => date(last),
+            })
+            .collect::<String>();
+        sanitize(&name)
+    }
+}
+
+/// Make `name` safe to use as a single path component on any common filesystem.
+///
+/// Path separators, characters that Windows doesn't allow, and control characters are replaced
+/// with `_`, as is whitespace. Leading and trailing dots are removed, so the name can't be `..` or
+/// a hidden file, and it is truncated to [`MAX_NAME_LENGTH`] bytes.
+pub fn sanitize(name: &str) -> String {
+    let replaced = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() || c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    let mut name = replaced.trim_matches('.').to_owned();
+    if name.len() > MAX_NAME_LENGTH {
+        let mut end = MAX_NAME_LENGTH;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        name = name.trim_end_matches('.').to_owned();
+    }
+
+    if name.is_empty() {
+        "archive".to_owned()
+    } else {
+        name
+    }
+}
+
+/// A name claimed for an archive, which no other archive will be given until this is dropped.
+#[derive(Debug)]
+pub struct ClaimedName {
+    pub name: String,
+}
+
+impl Drop for ClaimedName {
+    fn drop(&mut self) {
+        CLAIMED.lock().unwrap().remove(&self.name);
+    }
+}
+
+/// Claim a name based on `name` that no file in `storage` already has, so that writing an archive
+/// under it never overwrites an existing one.
+///
+/// If `name` is taken, `-2`, `-3`, etc. is appended until a free name is found.
+#[instrument(skip(storage))]
+pub async fn claim(storage: &Storage, name: &str) -> Result<ClaimedName> {
+    for n in 1.. {
+        let candidate = match n {
+            1 => name.to_owned(),
+            n => format!("{}-{}", name, n),
+        };
+        if CLAIMED.lock().unwrap().contains(&candidate) {
+            continue;
+        }
+
+        let mut taken = false;
+        for extension in EXTENSIONS {
+            if storage.exists(&format!("{candidate}.{extension}")).await? {
+                taken = true;
+                break;
+            }
+        }
+
+        // Another archive may have claimed the name while the storage was being checked
+        if !taken && CLAIMED.lock().unwrap().insert(candidate.clone()) {
+            trace!(name = %candidate, "Claimed name");
+            return Ok(ClaimedName { name: candidate });
+        }
+    }
+    unreachable!("There are infinitely many candidate names")
+}
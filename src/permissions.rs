@@ -0,0 +1,179 @@
+use crate::config;
+use crate::error::Error;
+use crate::Result;
+
+use serenity::model::channel::Channel;
+use serenity::model::channel::GuildChannel;
+use serenity::model::guild::Guild;
+use serenity::model::guild::Member;
+use serenity::model::id::ChannelId;
+use serenity::model::id::GuildId;
+use serenity::model::id::UserId;
+use serenity::model::Permissions;
+use serenity::prelude::Context;
+use tracing::*;
+
+/// The permissions a user needs in a channel to be able to read its history, and therefore to
+/// archive it.
+const READ_PERMISSIONS: Permissions =
+    Permissions::VIEW_CHANNEL.union(Permissions::READ_MESSAGE_HISTORY);
+
+/// Parse a comma-separated list of permission names (e.g. `MANAGE_MESSAGES` or `Manage Messages`),
+/// or a raw permission bitfield.
+pub fn parse_permissions(s: &str) -> std::result::Result<Permissions, String> {
+    if let Ok(bits) = s.parse::<u64>() {
+        return Permissions::from_bits(bits).ok_or_else(|| format!("Invalid permissions {}", bits));
+    }
+
+    let mut permissions = Permissions::empty();
+    for name in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let wanted = name.replace('_', " ");
+        let permission = (0..u64::BITS)
+            .map(|bit| Permissions::from_bits_truncate(1 << bit))
+            .find(|permission| {
+                permission
+                    .get_permission_names()
+                    .first()
+                    .is_some_and(|x| x.eq_ignore_ascii_case(&wanted))
+            })
+            .ok_or_else(|| format!("Unknown permission `{}`", name))?;
+        permissions |= permission;
+    }
+    Ok(permissions)
+}
+
+/// Check that the user `user_id`, who used a command in `channel_id`, is allowed to do so. If the
+/// command archives a channel, that channel is `target`.
+///
+/// The bot must be enabled in the guild, `target` must be in the same guild, and the user must have
+/// one of its allowed roles if any are configured.
+pub async fn authorize(
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    user_id: UserId,
+    target: Option<&GuildChannel>,
+) -> Result<()> {
+    if !config::get().is_guild_allowed(guild_id) {
+        return Err(Error::Forbidden(
+            "This bot is not enabled in this server".to_owned(),
+        ));
+    }
+
+    // Permissions are checked against this guild's roles, so they say nothing about another guild
+    if target.is_some_and(|target| target.guild_id != guild_id) {
+        return Err(Error::Forbidden(
+            "You can only archive channels in this server".to_owned(),
+        ));
+    }
+
+    let guild = guild_id
+        .to_guild_cached(ctx)
+        .ok_or_else(|| "Guild not found in cache".to_owned())?;
+    let member = guild.member(ctx, user_id).await?;
+
+    check_roles(&guild, &member)?;
+
+    match target {
+        Some(target) => check_archive_permissions(&guild, target, &member),
+        None => {
+            let channel = match guild.channels.get(&channel_id) {
+                Some(Channel::Guild(channel)) => channel,
+                _ => return Err("Command used outside of a guild text channel".into()),
+            };
+            check_command_permissions(&guild, channel, &member)
+        }
+    }
+}
+
+/// Check that the user `user_id` has `required` server-wide in `guild_id`, for commands that change
+/// the server rather than reading from it.
+pub async fn require_guild_permissions(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    required: Permissions,
+) -> Result<()> {
+    let guild = guild_id
+        .to_guild_cached(ctx)
+        .ok_or_else(|| "Guild not found in cache".to_owned())?;
+    let missing = required - guild.member_permissions(ctx, user_id).await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    warn!(user = %user_id, ?missing, "User does not have permission");
+
+    Err(Error::Forbidden(format!(
+        "You need the following permissions in this server to do this: {}",
+        missing.get_permission_names().join(", ")
+    )))
+}
+
+/// Check that `member` is allowed to archive `channel`: they must have the permissions required by
+/// the bot's configuration in that channel, and be able to read its history themselves.
+fn check_archive_permissions(guild: &Guild, channel: &GuildChannel, member: &Member) -> Result<()> {
+    check_permissions(
+        guild,
+        channel,
+        member,
+        config::get().guild(guild.id).required_permissions | READ_PERMISSIONS,
+    )
+}
+
+/// Check that `member` has the permissions required by the bot's configuration in `channel`, the
+/// channel a command was used in.
+fn check_command_permissions(guild: &Guild, channel: &GuildChannel, member: &Member) -> Result<()> {
+    check_permissions(
+        guild,
+        channel,
+        member,
+        config::get().guild(guild.id).required_permissions,
+    )
+}
+
+/// Check that `member` has at least one of the roles allowed to use the bot in `guild`, if the
+/// configuration restricts it to some roles.
+fn check_roles(guild: &Guild, member: &Member) -> Result<()> {
+    let allowed = &config::get().guild(guild.id).allowed_roles;
+    if allowed.is_empty() || member.roles.iter().any(|x| allowed.contains(x)) {
+        return Ok(());
+    }
+
+    warn!(user = %member.user.name, "User does not have an allowed role");
+
+    Err(Error::Forbidden(format!(
+        "You need one of the following roles to do this: {}",
+        allowed
+            .iter()
+            .map(|x| format!("<@&{}>", x))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+fn check_permissions(
+    guild: &Guild,
+    channel: &GuildChannel,
+    member: &Member,
+    required: Permissions,
+) -> Result<()> {
+    let permissions = guild.user_permissions_in(channel, member)?;
+    let missing = required - permissions;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        user = %member.user.name,
+        channel = %channel.name,
+        ?missing,
+        "User does not have permission"
+    );
+
+    Err(Error::Forbidden(format!(
+        "You need the following permissions in <#{}> to do this: {}",
+        channel.id,
+        missing.get_permission_names().join(", ")
+    )))
+}
@@ -0,0 +1,188 @@
+use crate::format_duration;
+use crate::model;
+
+use std::future::Future;
+use std::time::Duration;
+use std::time::Instant;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serenity::model::channel::GuildChannel;
+use tokio::sync::mpsc;
+use tracing::*;
+
+/// How often the progress message is edited. Editing more often than this risks being rate
+/// limited by Discord.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// An update on the progress of an archive, sent from the download loop to whatever is reporting
+/// it to the user.
+#[derive(Debug)]
+pub enum Progress {
+    /// A page of messages was downloaded.
+    Fetched {
+        /// The total number of messages downloaded so far.
+        count: usize,
+        /// The timestamp of the newest message in the channel.
+        newest: DateTime<Utc>,
+        /// The timestamp of the oldest message downloaded so far.
+        oldest: DateTime<Utc>,
+    },
+    /// The download is paused, e.g. after an error from Discord.
+    Waiting { duration: Duration },
+    /// All messages have been downloaded, and the output is being rendered.
+    Rendering,
+}
+
+pub type ProgressSender = mpsc::UnboundedSender<Progress>;
+pub type ProgressReceiver = mpsc::UnboundedReceiver<Progress>;
+
+pub fn channel() -> (ProgressSender, ProgressReceiver) {
+    mpsc::unbounded_channel()
+}
+
+/// Send `progress` to `sender`, if there is one.
+///
+/// Errors are ignored, as the receiver going away should not stop an archive.
+pub fn send(sender: Option<&ProgressSender>, progress: Progress) {
+    if let Some(sender) = sender {
+        let _ = sender.send(progress);
+    }
+}
+
+struct ProgressState {
+    channel_name: String,
+    /// When the channel was created. No message can be older than this, so it is the 100% mark.
+    channel_created: DateTime<Utc>,
+    started: Instant,
+    count: usize,
+    newest: Option<DateTime<Utc>>,
+    oldest: Option<DateTime<Utc>>,
+    waiting_until: Option<Instant>,
+    rendering: bool,
+}
+
+impl ProgressState {
+    fn apply(&mut self, progress: Progress) {
+        match progress {
+            Progress::Fetched {
+                count,
+                newest,
+                oldest,
+            } => {
+                self.count = count;
+                self.newest = Some(newest);
+                self.oldest = Some(oldest);
+                self.waiting_until = None;
+            }
+            Progress::Waiting { duration } => {
+                self.waiting_until = Some(Instant::now() + duration);
+            }
+            Progress::Rendering => {
+                self.rendering = true;
+                self.waiting_until = None;
+            }
+        }
+    }
+
+    /// The fraction of the channel's history that has been downloaded, estimated from how far
+    /// back in time the download has reached.
+    fn fraction(&self) -> f64 {
+        if self.rendering {
+            return 1.0;
+        }
+        match (self.newest, self.oldest) {
+            (Some(newest), Some(oldest)) => {
+                let total = (newest - self.channel_created).num_seconds();
+                if total <= 0 {
+                    return 1.0;
+                }
+                let done = (newest - oldest).num_seconds();
+                (done as f64 / total as f64).clamp(0.0, 1.0)
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn render(&self) -> String {
+        let fraction = self.fraction();
+        let filled = (fraction * PROGRESS_BAR_WIDTH as f64).round() as usize;
+
+        let mut out = format!(
+            "Archiving #{}\n`[{}{}]` {:.0}%\n",
+            self.channel_name,
+            "█".repeat(filled),
+            "░".repeat(PROGRESS_BAR_WIDTH - filled),
+            fraction * 100.0,
+        );
+
+        out.push_str(&format!("Fetched {} messages", self.count));
+        if let Some(oldest) = self.oldest {
+            out.push_str(&format!(", back to {}", oldest.format("%Y-%m-%d")));
+        }
+        out.push('\n');
+
+        if self.rendering {
+            out.push_str("Download complete, rendering output...");
+        } else if let Some(until) = self.waiting_until {
+            out.push_str(&format!(
+                "Waiting {} before retrying after an error from Discord",
+                format_duration(until.saturating_duration_since(Instant::now()))
+            ));
+        } else if fraction > 0.0 {
+            let elapsed = self.started.elapsed();
+            let eta = elapsed.mul_f64((1.0 - fraction) / fraction);
+            out.push_str(&format!("ETA: {}", format_duration(eta)));
+        }
+
+        out
+    }
+}
+
+/// Report progress from `receiver` by calling `update` with a description of it, at most once
+/// every [`UPDATE_INTERVAL`]. Returns once the sender is dropped.
+#[instrument(skip_all)]
+pub async fn report<F, Fut>(mut receiver: ProgressReceiver, channel: &GuildChannel, mut update: F)
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut state = ProgressState {
+        channel_name: channel.name.clone(),
+        channel_created: model::timestamp(channel.id.created_at()),
+        started: Instant::now(),
+        count: 0,
+        newest: None,
+        oldest: None,
+        waiting_until: None,
+        rendering: false,
+    };
+
+    let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+    let mut dirty = false;
+
+    loop {
+        tokio::select! {
+            progress = receiver.recv() => match progress {
+                Some(progress) => {
+                    trace!(?progress, "Received progress");
+                    state.apply(progress);
+                    dirty = true;
+                }
+                None => break,
+            },
+            _ = interval.tick() => {
+                if dirty {
+                    update(state.render()).await;
+                    dirty = false;
+                }
+            }
+        }
+    }
+
+    if dirty {
+        update(state.render()).await;
+    }
+}
@@ -0,0 +1,459 @@
+use crate::config;
+use crate::error::Error;
+use crate::file;
+use crate::model;
+use crate::model::ArchivedMessage;
+use crate::naming;
+use crate::retry;
+use crate::storage::Storage;
+use crate::upload;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use serenity::http::Http;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::channel::AttachmentType;
+use serenity::model::channel::Embed;
+use serenity::model::channel::GuildChannel;
+use serenity::model::guild::PremiumTier;
+use serenity::model::id::UserId;
+use serenity::model::webhook::Webhook;
+use serenity::prelude::Context;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+/// The name of the webhook messages are replayed through.
+const WEBHOOK_NAME: &str = "Archive replay";
+
+/// The time between messages. Discord allows a webhook to send 30 messages a minute in a channel.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many messages are replayed between updates to the command's response.
+const PROGRESS_INTERVAL: usize = 25;
+
+/// The longest message Discord allows.
+const MAX_CONTENT_LENGTH: usize = 2000;
+
+/// The longest name a webhook message can be sent under.
+const MAX_USERNAME_LENGTH: usize = 80;
+
+/// The most embeds Discord allows on a message.
+const MAX_EMBEDS: usize = 10;
+
+/// Message types that a user sent, as opposed to system messages such as joins and pins: default
+/// messages, replies, and slash and context menu command responses.
+const USER_MESSAGE_KINDS: &[u8] = &[0, 19, 20, 23];
+
+/// Replays in progress, as `{archived channel}-{target channel}`, so that the same archive isn't
+/// replayed into the same channel twice at once.
+static REPLAYING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Releases a replay from [`REPLAYING`] when dropped.
+struct ReplayGuard(String);
+
+impl Drop for ReplayGuard {
+    fn drop(&mut self) {
+        REPLAYING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Repost the messages in `archive_key`, a JSON archive in [`Storage::output`], into `target`
+/// through a webhook that takes on the name and avatar of each message's author, returning a
+/// description of what was replayed.
+///
+/// Messages are sent oldest first, one every [`REPLAY_INTERVAL`]. The last message replayed is
+/// recorded in the output path, so a replay that is interrupted or cancelled carries on from where
+/// it stopped when run again, and replaying a finished archive again sends nothing.
+#[instrument(skip_all, fields(archive = %archive_key, target = %target.name))]
+pub async fn replay(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    archive_key: &str,
+    target: &GuildChannel,
+    tier: PremiumTier,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    // Only allow the names archives are written under, so that nothing outside the storage is read
+    if naming::sanitize(archive_key) != archive_key || !archive_key.ends_with(".json") {
+        return Err(format!(
+            "`{}` is not the name of a JSON archive, such as `server-channel.json`",
+            archive_key
+        )
+        .into());
+    }
+    let json = Storage::output().read(archive_key).await.map_err(|e| {
+        warn!(error = ?e, "Failed to read archive");
+        format!("No archive `{}` was found", archive_key)
+    })?;
+    let archive = model::read_archive(&String::from_utf8_lossy(&json))?;
+
+    let key = format!("{}-{}.txt", archive.channel.id, target.id);
+    if !REPLAYING.lock().unwrap().insert(key.clone()) {
+        return Err(format!(
+            "This archive is already being replayed into <#{}>",
+            target.id
+        )
+        .into());
+    }
+    let _guard = ReplayGuard(key.clone());
+
+    // Kept in the output path, rather than the storage, like archive checkpoints
+    let state = Storage::local(config::get().output_path.join(".replays"));
+    let replayed_up_to = match state.exists(&key).await? {
+        true => String::from_utf8_lossy(&state.read(&key).await?)
+            .trim()
+            .parse::<u64>()
+            .ok(),
+        false => None,
+    };
+
+    let mut messages = archive
+        .messages
+        .iter()
+        .filter(|message| USER_MESSAGE_KINDS.contains(&message.kind))
+        .filter(|message| replayed_up_to.is_none_or(|id| message.id > id))
+        .collect::<Vec<_>>();
+    // Messages recovered from a watch log were deleted by their authors, so don't repost them
+    let deleted = messages.iter().filter(|x| x.deleted_at.is_some()).count();
+    messages.retain(|message| message.deleted_at.is_none());
+    messages.sort_by_key(|message| message.id);
+
+    info!(
+        count = %messages.len(),
+        %deleted,
+        ?replayed_up_to,
+        "Replaying archive"
+    );
+
+    if messages.is_empty() {
+        return Ok(match replayed_up_to {
+            Some(_) => format!(
+                "Every message in `{}` has already been replayed into <#{}>",
+                archive_key, target.id
+            ),
+            None => format!("`{}` has no messages to replay", archive_key),
+        });
+    }
+
+    let webhook = find_or_create_webhook(&ctx.http, target, ctx.cache.current_user_id()).await?;
+    let nicks = archive
+        .members
+        .iter()
+        .filter_map(|member| Some((member.user.id, member.nick.clone()?)))
+        .collect::<HashMap<_, _>>();
+    let upload_limit = upload::upload_limit(tier);
+
+    let total = messages.len();
+    for (i, message) in messages.into_iter().enumerate() {
+        let outgoing = prepare(message, &nicks, upload_limit).await;
+        for (j, content) in outgoing.contents.iter().enumerate() {
+            // Files and embeds go with the last part of a long message
+            let last = j + 1 == outgoing.contents.len();
+            send(
+                &ctx.http, &webhook, &outgoing, content, last, cancel, target,
+            )
+            .await?;
+            tokio::select! {
+                _ = tokio::time::sleep(REPLAY_INTERVAL) => {},
+                _ = cancel.cancelled() => return Err(Error::Cancelled),
+            }
+        }
+
+        state.write(&key, message.id.to_string()).await?;
+
+        if (i + 1) % PROGRESS_INTERVAL == 0 {
+            let text = format!("Replaying `{}`: {}/{} messages", archive_key, i + 1, total);
+            if let Err(error) = command
+                .edit_original_interaction_response(ctx, |builder| builder.content(text))
+                .await
+            {
+                warn!(?error, "Failed to report progress");
+            }
+        }
+    }
+
+    info!(%total, "Replay complete");
+
+    let mut response = format!(
+        "Replayed {} messages from `{}` into <#{}>",
+        total, archive_key, target.id
+    );
+    if deleted > 0 {
+        response += &format!(
+            "\nSkipped {} messages that were deleted before the archive was made",
+            deleted
+        );
+    }
+    Ok(response)
+}
+
+/// The webhook in `target` that this bot uses for replays, creating it if there isn't one.
+async fn find_or_create_webhook(
+    http: &Http,
+    target: &GuildChannel,
+    bot: UserId,
+) -> Result<Webhook> {
+    let existing = target.id.webhooks(http).await?.into_iter().find(|webhook| {
+        webhook.name.as_deref() == Some(WEBHOOK_NAME)
+            && webhook.token.is_some()
+            && webhook.user.as_ref().is_some_and(|user| user.id == bot)
+    });
+    match existing {
+        Some(x) => Ok(x),
+        None => {
+            info!("Creating webhook");
+            Ok(target.id.create_webhook(http, WEBHOOK_NAME).await?)
+        }
+    }
+}
+
+/// A message ready to send through the webhook.
+struct Outgoing {
+    username: String,
+    avatar_url: String,
+    /// The content, split into parts short enough to send. Always has at least one part.
+    contents: Vec<String>,
+    files: Vec<(String, Vec<u8>)>,
+    embeds: Vec<Value>,
+}
+
+/// Build the message to send for `message`, downloading its attachments.
+async fn prepare(
+    message: &ArchivedMessage,
+    nicks: &HashMap<u64, String>,
+    upload_limit: u64,
+) -> Outgoing {
+    let mut content = message.content.clone();
+    let mut notes = Vec::new();
+
+    let mut files = Vec::new();
+    let mut total_size = 0;
+    for attachment in &message.attachments {
+        if total_size + attachment.size > upload_limit {
+            notes.push(format!(
+                "[Attachment: {}]({})",
+                attachment.filename, attachment.url
+            ));
+            continue;
+        }
+        match file::download_url(&attachment.url).await {
+            Ok(download) => {
+                total_size += download.bytes.len() as u64;
+                files.push((attachment.filename.clone(), download.bytes));
+            }
+            Err(error) => {
+                // Attachment URLs expire, so this is expected for old archives
+                warn!(?error, id = %attachment.id, "Failed to download attachment");
+                notes.push(format!(
+                    "[Attachment: {} (no longer available)]({})",
+                    attachment.filename, attachment.url
+                ));
+            }
+        }
+    }
+    for sticker in &message.stickers {
+        notes.push(format!("[Sticker: {}]", sticker.name));
+    }
+    if !notes.is_empty() {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content += &notes.join("\n");
+    }
+
+    // Discord generates link previews again from the content, so only send embeds that aren't
+    // previews of a link in it
+    let embeds = message
+        .embeds
+        .iter()
+        .filter(|embed| {
+            embed
+                .url
+                .as_ref()
+                .is_none_or(|url| !message.content.contains(url.as_str()))
+        })
+        .filter(|embed| embed.description.is_some() || !embed.fields.is_empty())
+        .take(MAX_EMBEDS)
+        .map(|embed| {
+            Embed::fake(|builder| {
+                if let Some(x) = &embed.title {
+                    builder.title(x);
+                }
+                if let Some(x) = &embed.description {
+                    builder.description(x);
+                }
+                if let Some(x) = &embed.url {
+                    builder.url(x);
+                }
+                if let Some(x) = embed.colour {
+                    builder.colour(x);
+                }
+                if let Some(x) = &embed.image_url {
+                    builder.image(x);
+                }
+                if let Some(x) = &embed.thumbnail_url {
+                    builder.thumbnail(x);
+                }
+                if let Some(x) = &embed.footer_text {
+                    builder.footer(|footer| footer.text(x));
+                }
+                for field in &embed.fields {
+                    builder.field(&field.name, &field.value, field.inline);
+                }
+                builder
+            })
+        })
+        .collect();
+
+    let name = nicks
+        .get(&message.author.id)
+        .unwrap_or(&message.author.name);
+    let time = message.timestamp.format(" · %Y-%m-%d %H:%M");
+
+    Outgoing {
+        username: username(name, &time.to_string()),
+        avatar_url: message.author.avatar_url.clone(),
+        contents: split_content(&content),
+        files,
+        embeds,
+    }
+}
+
+/// The name to send a message from `name` under, with `suffix` (the time it was sent) after it.
+///
+/// Webhook names can't contain "discord" or "clyde", so those are broken up with a zero-width
+/// space.
+fn username(name: &str, suffix: &str) -> String {
+    let mut broken = String::with_capacity(name.len());
+    for (i, c) in name.char_indices() {
+        broken.push(c);
+        // Matched in `name` itself, as lowercasing can change the length of earlier characters
+        let rest = &name.as_bytes()[i..];
+        let starts_word = ["discord", "clyde"].iter().any(|word| {
+            rest.get(..word.len())
+                .is_some_and(|x| x.eq_ignore_ascii_case(word.as_bytes()))
+        });
+        if starts_word {
+            broken.push('\u{200B}');
+        }
+    }
+
+    let limit = MAX_USERNAME_LENGTH - suffix.chars().count();
+    let mut name = broken.chars().take(limit).collect::<String>();
+    name += suffix;
+    name
+}
+
+/// Split `content` into parts of at most [`MAX_CONTENT_LENGTH`] characters, preferring to split
+/// at line breaks.
+fn split_content(content: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = content;
+    while rest.chars().count() > MAX_CONTENT_LENGTH {
+        let end = rest
+            .char_indices()
+            .nth(MAX_CONTENT_LENGTH)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let split = rest[..end].rfind('\n').filter(|x| *x > 0).unwrap_or(end);
+        parts.push(rest[..split].to_owned());
+        rest = rest[split..].trim_start_matches('\n');
+    }
+    parts.push(rest.to_owned());
+    parts
+}
+
+/// Send `content` through `webhook`, with the files and embeds of `outgoing` if `last`, retrying
+/// temporary errors.
+async fn send(
+    http: &Http,
+    webhook: &Webhook,
+    outgoing: &Outgoing,
+    content: &str,
+    last: bool,
+    cancel: &CancellationToken,
+    target: &GuildChannel,
+) -> Result<()> {
+    if content.is_empty() && !(last && (!outgoing.files.is_empty() || !outgoing.embeds.is_empty()))
+    {
+        return Ok(());
+    }
+
+    let mut backoff = retry::Backoff::default();
+    loop {
+        let result = webhook
+            .execute(http, true, |builder| {
+                builder
+                    .username(&outgoing.username)
+                    .avatar_url(&outgoing.avatar_url)
+                    .content(content)
+                    // Replayed messages shouldn't ping anyone
+                    .allowed_mentions(|mentions| mentions.empty_parse());
+                if last {
+                    builder
+                        .embeds(outgoing.embeds.clone())
+                        .add_files(outgoing.files.iter().map(|(filename, data)| {
+                            AttachmentType::Bytes {
+                                data: data.as_slice().into(),
+                                filename: filename.clone(),
+                            }
+                        }));
+                }
+                builder
+            })
+            .await;
+        let error = match result {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let delay = match retry::is_retryable(&error) {
+            true => backoff.next_delay(),
+            false => None,
+        };
+        let delay = match delay {
+            Some(x) => x,
+            None => {
+                error!(?error, "Failed to replay message");
+                return Err(format!(
+                    "{}\nReplaying the archive into <#{}> again will resume from where this \
+                    stopped.",
+                    retry::describe_failure(&error, target),
+                    target.id
+                )
+                .into());
+            }
+        };
+
+        warn!(?error, ?delay, "Failed to replay message. Retrying");
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = cancel.cancelled() => return Err(Error::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_breaks_up_forbidden_words() {
+        assert_eq!(username("Discord fan", ""), "D\u{200B}iscord fan");
+        assert_eq!(username("ClydeCLYDE", ""), "C\u{200B}lydeC\u{200B}LYDE");
+    }
+
+    #[test]
+    fn username_handles_non_ascii_prefixes() {
+        // KELVIN SIGN lowercases to a shorter "k", and OHM SIGN to a shorter "ω"
+        assert_eq!(username("\u{212A}discord", ""), "\u{212A}d\u{200B}iscord");
+        assert_eq!(username("\u{2126}discord", ""), "\u{2126}d\u{200B}iscord");
+        assert_eq!(username("ééclyde", " · x"), "ééc\u{200B}lyde · x");
+    }
+}
@@ -0,0 +1,687 @@
+use crate::emoji::ArchivedEmoji;
+use crate::emoji::ArchivedSticker;
+use crate::emoji::EmojiManifest;
+use crate::emoji::MANIFEST_FILE;
+use crate::naming;
+use crate::permissions;
+use crate::storage::Storage;
+use crate::structure::GuildStructure;
+use crate::structure::StructureChannel;
+use crate::structure::STRUCTURE_FILE;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serenity::http::Http;
+use serenity::model::channel::AttachmentType;
+use serenity::model::channel::ChannelType;
+use serenity::model::channel::PermissionOverwrite;
+use serenity::model::channel::PermissionOverwriteType;
+use serenity::model::guild::Guild;
+use serenity::model::guild::PremiumTier;
+use serenity::model::id::ChannelId;
+use serenity::model::id::RoleId;
+use serenity::model::id::UserId;
+use serenity::model::Permissions;
+use tracing::*;
+
+/// The largest image Discord accepts for an emoji.
+const MAX_EMOJI_SIZE: usize = 256 * 1024;
+
+/// The largest file Discord accepts for a sticker.
+const MAX_STICKER_SIZE: usize = 512 * 1024;
+
+/// The most problems listed in the response, to keep it within Discord's message length limit.
+const MAX_LISTED: usize = 15;
+
+/// How many static emoji, and separately how many animated emoji, a guild with boost level `tier`
+/// can have.
+fn emoji_limit(tier: PremiumTier) -> usize {
+    match tier {
+        PremiumTier::Tier1 => 100,
+        PremiumTier::Tier2 => 150,
+        PremiumTier::Tier3 => 250,
+        _ => 50,
+    }
+}
+
+/// How many stickers a guild with boost level `tier` can have.
+fn sticker_limit(tier: PremiumTier) -> usize {
+    match tier {
+        PremiumTier::Tier1 => 15,
+        PremiumTier::Tier2 => 30,
+        PremiumTier::Tier3 => 60,
+        _ => 5,
+    }
+}
+
+/// The outcome of restoring an emoji archive.
+#[derive(Default)]
+struct RestoreLog {
+    emoji: usize,
+    stickers: usize,
+    /// The names of those already in the guild.
+    skipped: Vec<String>,
+    /// The names of those that could not be restored, and why.
+    failed: Vec<(String, String)>,
+}
+
+/// Upload the emoji and stickers in `directory`, an archive made by
+/// [`archive_emoji`](crate::emoji::archive_emoji) in [`Storage::output`], to `guild`, returning a
+/// description of what was restored.
+///
+/// Emoji and stickers with the same name as one already in `guild` are skipped, as are any that
+/// would go over the guild's limits. Role restrictions are not restored, as the roles belong to the
+/// archived guild.
+#[instrument(skip(http, guild), fields(guild = %guild.name))]
+pub async fn restore_emoji(http: &Http, guild: &Guild, directory: &str) -> Result<String> {
+    let storage = Storage::output();
+    let manifest: EmojiManifest =
+        read_backup(storage, directory, MANIFEST_FILE, "/archive_emoji").await?;
+
+    info!(
+        from = %manifest.guild_name,
+        emoji = %manifest.emoji.len(),
+        stickers = %manifest.stickers.len(),
+        "Restoring emoji"
+    );
+
+    let mut log = RestoreLog::default();
+    restore_emoji_images(http, guild, storage, directory, &manifest.emoji, &mut log).await;
+    restore_stickers(
+        http,
+        guild,
+        storage,
+        directory,
+        &manifest.stickers,
+        &mut log,
+    )
+    .await;
+
+    info!(
+        emoji = %log.emoji,
+        stickers = %log.stickers,
+        skipped = %log.skipped.len(),
+        failed = %log.failed.len(),
+        "Restore complete"
+    );
+
+    Ok(describe(&log, &manifest.guild_name))
+}
+
+async fn restore_emoji_images(
+    http: &Http,
+    guild: &Guild,
+    storage: &Storage,
+    directory: &str,
+    emojis: &[ArchivedEmoji],
+    log: &mut RestoreLog,
+) {
+    let limit = emoji_limit(guild.premium_tier);
+    let mut names = guild
+        .emojis
+        .values()
+        .map(|x| x.name.clone())
+        .collect::<HashSet<_>>();
+    let mut animated = guild.emojis.values().filter(|x| x.animated).count();
+    let mut still = guild.emojis.len() - animated;
+
+    for emoji in emojis {
+        if names.contains(&emoji.name) {
+            log.skipped.push(format!(":{}:", emoji.name));
+            continue;
+        }
+
+        let mut fail = |reason: &str| {
+            log.failed
+                .push((format!(":{}:", emoji.name), reason.into()))
+        };
+        if emoji.managed {
+            fail("managed by an integration");
+            continue;
+        }
+        let count = match emoji.animated {
+            true => &mut animated,
+            false => &mut still,
+        };
+        if *count >= limit {
+            fail(match emoji.animated {
+                true => "no free animated emoji slots",
+                false => "no free emoji slots",
+            });
+            continue;
+        }
+        let file = match &emoji.file {
+            Some(x) => x,
+            None => {
+                fail("not downloaded when it was archived");
+                continue;
+            }
+        };
+
+        let result: Result<()> = async {
+            let data = storage.read(&format!("{}/{}", directory, file)).await?;
+            if data.len() > MAX_EMOJI_SIZE {
+                return Err("larger than Discord's 256 KiB limit".into());
+            }
+            let image = format!("data:{};base64,{}", mime_type(file), BASE64.encode(data));
+            guild.id.create_emoji(http, &emoji.name, &image).await?;
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                trace!(name = %emoji.name, "Restored emoji");
+                *count += 1;
+                log.emoji += 1;
+                names.insert(emoji.name.clone());
+            }
+            Err(e) => {
+                warn!(error = ?e, name = %emoji.name, "Failed to restore emoji");
+                fail(&e.to_string());
+            }
+        }
+    }
+}
+
+async fn restore_stickers(
+    http: &Http,
+    guild: &Guild,
+    storage: &Storage,
+    directory: &str,
+    stickers: &[ArchivedSticker],
+    log: &mut RestoreLog,
+) {
+    let limit = sticker_limit(guild.premium_tier);
+    let mut names = guild
+        .stickers
+        .values()
+        .map(|x| x.name.clone())
+        .collect::<HashSet<_>>();
+    let mut count = guild.stickers.len();
+
+    for sticker in stickers {
+        if names.contains(&sticker.name) {
+            log.skipped.push(format!("sticker {}", sticker.name));
+            continue;
+        }
+
+        let mut fail = |reason: &str| {
+            log.failed
+                .push((format!("sticker {}", sticker.name), reason.into()))
+        };
+        if count >= limit {
+            fail("no free sticker slots");
+            continue;
+        }
+        let file = match &sticker.file {
+            Some(x) => x,
+            None => {
+                fail("not downloaded when it was archived");
+                continue;
+            }
+        };
+
+        let result: Result<()> = async {
+            let data = storage.read(&format!("{}/{}", directory, file)).await?;
+            if data.len() > MAX_STICKER_SIZE {
+                return Err("larger than Discord's 512 KiB limit".into());
+            }
+            // Discord requires at least one tag, the name of an emoji related to the sticker
+            let tags = match sticker.tags.is_empty() {
+                true => sticker.name.clone(),
+                false => sticker.tags.join(","),
+            };
+            let filename = file.rsplit('/').next().unwrap_or(file).to_owned();
+            guild
+                .id
+                .create_sticker(http, |builder| {
+                    builder
+                        .name(&sticker.name)
+                        .description(sticker.description.as_deref().unwrap_or_default())
+                        .tags(tags)
+                        .file(AttachmentType::Bytes {
+                            data: data.into(),
+                            filename,
+                        })
+                })
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                trace!(name = %sticker.name, "Restored sticker");
+                count += 1;
+                log.stickers += 1;
+                names.insert(sticker.name.clone());
+            }
+            Err(e) => {
+                warn!(error = ?e, name = %sticker.name, "Failed to restore sticker");
+                fail(&e.to_string());
+            }
+        }
+    }
+}
+
+/// The highest voice channel bitrate a guild with boost level `tier` can use, in bits per second.
+fn max_bitrate(tier: PremiumTier) -> u64 {
+    match tier {
+        PremiumTier::Tier1 => 128_000,
+        PremiumTier::Tier2 => 256_000,
+        PremiumTier::Tier3 => 384_000,
+        _ => 96_000,
+    }
+}
+
+/// The outcome of restoring a structure backup.
+pub struct StructureRestoreLog {
+    pub summary: String,
+    /// Each change made, or that would be made in a dry run, one per line.
+    pub changes: Vec<String>,
+}
+
+/// Makes the changes needed to restore a structure backup, or in a dry run, only describes them.
+struct Restorer {
+    dry_run: bool,
+    changes: Vec<String>,
+    created: usize,
+    failed: usize,
+}
+
+impl Restorer {
+    /// Run `change`, described by `description` (e.g. "role `Mods`"), returning what it created.
+    /// In a dry run, `change` isn't run and `None` is returned.
+    async fn apply<T>(
+        &mut self,
+        description: String,
+        change: impl Future<Output = serenity::Result<T>>,
+    ) -> Option<T> {
+        if self.dry_run {
+            self.changes.push(format!("Would create {}", description));
+            self.created += 1;
+            return None;
+        }
+
+        match change.await {
+            Ok(x) => {
+                trace!(%description, "Created");
+                self.changes.push(format!("Created {}", description));
+                self.created += 1;
+                Some(x)
+            }
+            Err(e) => {
+                warn!(error = ?e, %description, "Failed to create");
+                self.changes
+                    .push(format!("Failed to create {}: {}", description, e));
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn note(&mut self, note: String) {
+        self.changes.push(note);
+    }
+}
+
+/// Recreate the roles, categories and channels in `directory`, a backup made by
+/// [`archive_structure`](crate::structure::archive_structure) in [`Storage::output`], in `guild`.
+/// If `dry_run` is set, nothing is changed, and the changes that would be made are described
+/// instead.
+///
+/// `guild` must not have any roles of its own, so that restoring can't duplicate them. Permission
+/// overwrites for roles are mapped to the new roles, and those for members are kept if the member
+/// is in `guild`. Settings and images are not restored.
+#[instrument(skip(http, guild), fields(guild = %guild.name))]
+pub async fn restore_structure(
+    http: &Http,
+    guild: &Guild,
+    directory: &str,
+    dry_run: bool,
+) -> Result<StructureRestoreLog> {
+    let structure: GuildStructure = read_backup(
+        Storage::output(),
+        directory,
+        STRUCTURE_FILE,
+        "/archive_structure",
+    )
+    .await?;
+
+    let existing = guild
+        .roles
+        .values()
+        .filter(|role| !role.managed && role.id.0 != guild.id.0)
+        .map(|role| format!("`{}`", role.name))
+        .collect::<Vec<_>>();
+    if !existing.is_empty() {
+        return Err(format!(
+            "This server already has roles ({}). A structure backup can only be restored into a \
+            server without roles of its own, so that nothing is duplicated.",
+            existing.join(", ")
+        )
+        .into());
+    }
+
+    info!(from = %structure.settings.name, %dry_run, "Restoring structure");
+
+    let mut restorer = Restorer {
+        dry_run,
+        changes: Vec::new(),
+        created: 0,
+        failed: 0,
+    };
+
+    // The `@everyone` role's ID is the guild's ID
+    let everyone = RoleId(guild.id.0);
+    let mut roles = HashMap::from([(structure.settings.id, everyone)]);
+
+    // New roles are added at the bottom of the list, so creating them from the top down keeps them
+    // in order
+    for role in &structure.roles {
+        let permissions = parse_permission_names(&role.permissions);
+        if role.id == structure.settings.id {
+            let change = guild
+                .id
+                .edit_role(http, everyone, |x| x.permissions(permissions));
+            restorer
+                .apply("the permissions of `@everyone`".to_owned(), change)
+                .await;
+            continue;
+        }
+        if role.managed {
+            restorer.note(format!(
+                "Skipped role `{}`, which is managed by an integration",
+                role.name
+            ));
+            continue;
+        }
+
+        let colour = role
+            .colour
+            .as_deref()
+            .and_then(|x| u64::from_str_radix(x.trim_start_matches('#'), 16).ok())
+            .unwrap_or(0);
+        let change = guild.id.create_role(http, |x| {
+            x.name(&role.name)
+                .colour(colour)
+                .hoist(role.hoist)
+                .mentionable(role.mentionable)
+                .permissions(permissions)
+        });
+        match restorer
+            .apply(format!("role `{}`", role.name), change)
+            .await
+        {
+            Some(created) => {
+                roles.insert(role.id, created.id);
+            }
+            // Later changes are still described in terms of the backup's roles
+            None if dry_run => {
+                roles.insert(role.id, RoleId(role.id));
+            }
+            None => {}
+        }
+    }
+
+    let bitrate = max_bitrate(guild.premium_tier);
+    for category in &structure.categories {
+        let description = format!("category `{}`", category.category.name);
+        let created = restore_channel(
+            http,
+            guild,
+            &category.category,
+            None,
+            &roles,
+            bitrate,
+            description,
+            &mut restorer,
+        )
+        .await;
+        let parent = match created {
+            Some(x) => Some(x),
+            None if dry_run => Some(ChannelId(category.category.id)),
+            // Put the channels at the top level rather than losing them
+            None => None,
+        };
+
+        for channel in &category.channels {
+            let description = format!(
+                "{} channel `#{}` in `{}`",
+                channel.kind, channel.name, category.category.name
+            );
+            restore_channel(
+                http,
+                guild,
+                channel,
+                parent,
+                &roles,
+                bitrate,
+                description,
+                &mut restorer,
+            )
+            .await;
+        }
+    }
+    for channel in &structure.channels {
+        let description = format!("{} channel `#{}`", channel.kind, channel.name);
+        restore_channel(
+            http,
+            guild,
+            channel,
+            None,
+            &roles,
+            bitrate,
+            description,
+            &mut restorer,
+        )
+        .await;
+    }
+
+    info!(
+        created = %restorer.created,
+        failed = %restorer.failed,
+        "Structure restore complete"
+    );
+    if restorer.changes.is_empty() {
+        restorer.note("The backup has no roles or channels to restore".to_owned());
+    }
+
+    let summary = match dry_run {
+        true => format!(
+            "Dry run: restoring **{}** would make {} changes, listed in the attached plan. Run \
+            `/restore_structure` again with `dry_run` set to false to make them.",
+            structure.settings.name, restorer.created
+        ),
+        false => format!(
+            "Restored **{}**: made {} changes, and {} failed. See the attached log for details.",
+            structure.settings.name, restorer.created, restorer.failed
+        ),
+    };
+
+    Ok(StructureRestoreLog {
+        summary,
+        changes: restorer.changes,
+    })
+}
+
+/// Create `channel` in `parent`, returning the ID of the new channel.
+#[allow(clippy::too_many_arguments)]
+async fn restore_channel(
+    http: &Http,
+    guild: &Guild,
+    channel: &StructureChannel,
+    parent: Option<ChannelId>,
+    roles: &HashMap<u64, RoleId>,
+    max_bitrate: u64,
+    description: String,
+    restorer: &mut Restorer,
+) -> Option<ChannelId> {
+    let kind = match channel.kind.as_str() {
+        "text" => ChannelType::Text,
+        "voice" => ChannelType::Voice,
+        "category" => ChannelType::Category,
+        "news" => ChannelType::News,
+        "stage" => ChannelType::Stage,
+        "forum" => ChannelType::Forum,
+        _ => {
+            restorer.note(format!(
+                "Skipped {}, as channels of this kind can't be created",
+                description
+            ));
+            return None;
+        }
+    };
+
+    let mut overwrites = Vec::new();
+    for overwrite in &channel.permission_overwrites {
+        let kind = match overwrite.kind.as_str() {
+            "role" => roles
+                .get(&overwrite.id)
+                .map(|x| PermissionOverwriteType::Role(*x)),
+            "member" => {
+                let user = UserId(overwrite.id);
+                guild
+                    .members
+                    .contains_key(&user)
+                    .then_some(PermissionOverwriteType::Member(user))
+            }
+            _ => None,
+        };
+        match kind {
+            Some(kind) => overwrites.push(PermissionOverwrite {
+                allow: parse_permission_names(&overwrite.allow),
+                deny: parse_permission_names(&overwrite.deny),
+                kind,
+            }),
+            None => restorer.note(format!(
+                "Skipped the permission overwrite for {} `{}` on {}, as it isn't in this server",
+                overwrite.kind,
+                overwrite.name.as_deref().unwrap_or("unknown"),
+                description
+            )),
+        }
+    }
+
+    let change = guild.id.create_channel(http, |x| {
+        x.name(&channel.name)
+            .kind(kind)
+            .nsfw(channel.nsfw)
+            .position(channel.position.clamp(0, u32::MAX as i64) as u32)
+            .permissions(overwrites);
+        if let Some(parent) = parent {
+            x.category(parent);
+        }
+        if let Some(topic) = &channel.topic {
+            x.topic(topic);
+        }
+        if let Some(slowmode) = channel.slowmode.filter(|x| *x > 0) {
+            x.rate_limit_per_user(slowmode);
+        }
+        if let Some(bitrate) = channel.bitrate {
+            x.bitrate(bitrate.min(max_bitrate) as u32);
+        }
+        if let Some(user_limit) = channel.user_limit.filter(|x| *x > 0) {
+            x.user_limit(user_limit as u32);
+        }
+        x
+    });
+    restorer
+        .apply(description, change)
+        .await
+        .map(|created| created.id)
+}
+
+/// Parse permission names from a structure backup, skipping any that aren't known.
+fn parse_permission_names(names: &[String]) -> Permissions {
+    let mut permissions = Permissions::empty();
+    for name in names {
+        match permissions::parse_permissions(name) {
+            Ok(x) => permissions |= x,
+            Err(e) => warn!(%e, "Skipping unknown permission"),
+        }
+    }
+    permissions
+}
+
+/// Read and parse `file` from `directory`, a backup made by `command` in `storage`.
+async fn read_backup<T: DeserializeOwned>(
+    storage: &Storage,
+    directory: &str,
+    file: &str,
+    command: &str,
+) -> Result<T> {
+    // Only allow the names the archive commands create, so that nothing outside the storage is
+    // read
+    if directory.is_empty() || naming::sanitize(directory) != directory {
+        return Err(format!("`{}` is not a valid backup name", directory).into());
+    }
+
+    let contents = storage
+        .read(&format!("{}/{}", directory, file))
+        .await
+        .map_err(|e| {
+            warn!(error = ?e, %directory, "Failed to read backup");
+            format!(
+                "No backup `{}` was found. Use the name of a directory created by `{}`.",
+                directory, command
+            )
+        })?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+fn mime_type(file: &str) -> &'static str {
+    match file.rsplit('.').next() {
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("jpg") => "image/jpeg",
+        _ => "image/png",
+    }
+}
+
+fn describe(log: &RestoreLog, from: &str) -> String {
+    let mut response = format!(
+        "Restored {} emoji and {} stickers from **{}**.",
+        log.emoji, log.stickers, from
+    );
+
+    if !log.skipped.is_empty() {
+        response += &format!(
+            "\nSkipped {} already in this server: {}",
+            log.skipped.len(),
+            truncated(log.skipped.iter().map(|x| format!("`{}`", x)), ", ")
+        );
+    }
+
+    if !log.failed.is_empty() {
+        response += &format!(
+            "\nCould not restore {}:\n{}",
+            log.failed.len(),
+            truncated(
+                log.failed
+                    .iter()
+                    .map(|(name, reason)| format!("- `{}`: {}", name, reason)),
+                "\n"
+            )
+        );
+    }
+
+    response
+}
+
+/// Join the first [`MAX_LISTED`] of `items` with `separator`, noting how many were left out.
+fn truncated(items: impl ExactSizeIterator<Item = String>, separator: &str) -> String {
+    let remaining = items.len().saturating_sub(MAX_LISTED);
+    let mut joined = items.take(MAX_LISTED).collect::<Vec<_>>().join(separator);
+    if remaining > 0 {
+        joined += &format!("{}and {} more", separator, remaining);
+    }
+    joined
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serenity::http::error::Error as HttpError;
+use serenity::http::StatusCode;
+use serenity::model::channel::GuildChannel;
+
+/// The delay before the first retry. Each subsequent retry waits up to twice as long.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest a single retry will wait.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times a request is attempted before giving up.
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// Exponential backoff with jitter, for retrying requests to Discord.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Record a failed attempt, returning how long to wait before the next one, or `None` if no
+    /// attempts remain.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        if self.attempts >= MAX_ATTEMPTS {
+            return None;
+        }
+
+        let ceiling = BASE_DELAY
+            .saturating_mul(1 << (self.attempts - 1).min(16))
+            .min(MAX_DELAY);
+        // Wait between half and all of the ceiling, so that concurrent jobs that fail together
+        // don't retry together.
+        let half = ceiling / 2;
+        Some(half + rand::thread_rng().gen_range(Duration::ZERO..=half))
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+/// Whether a request that failed with `error` is worth retrying: rate limits, server errors, and
+/// network errors are, whereas e.g. a missing permission will fail the same way every time.
+///
+/// serenity's ratelimiter already waits for the `Retry-After` of a 429 and retries the request
+/// itself, so a 429 only reaches us if Discord did not say how long to wait.
+pub fn is_retryable(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(error) => match &**error {
+            HttpError::UnsuccessfulRequest(response) => {
+                response.status_code == StatusCode::TOO_MANY_REQUESTS
+                    || response.status_code.is_server_error()
+            }
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        serenity::Error::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// Explain why downloading messages from `channel` failed with `error`, in terms the user can act
+/// on.
+pub fn describe_failure(error: &serenity::Error, channel: &GuildChannel) -> String {
+    let response = match error {
+        serenity::Error::Http(error) => match &**error {
+            HttpError::UnsuccessfulRequest(response) => Some(response),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match response {
+        Some(response) if response.status_code == StatusCode::UNAUTHORIZED => {
+            "Discord rejected the bot's token".to_owned()
+        }
+        Some(response) if response.status_code == StatusCode::FORBIDDEN => format!(
+            "The bot does not have access to <#{}>. \
+            It needs the View Channel and Read Message History permissions there.",
+            channel.id
+        ),
+        Some(response) if response.status_code == StatusCode::NOT_FOUND => {
+            format!("#{} no longer exists", channel.name)
+        }
+        Some(response) => format!(
+            "Discord returned an error while downloading messages from <#{}>: {} ({})",
+            channel.id, response.error.message, response.status_code
+        ),
+        None => format!(
+            "Failed to download messages from <#{}>: {}",
+            channel.id, error
+        ),
+    }
+}
@@ -0,0 +1,304 @@
+use crate::archive;
+use crate::archive_response;
+use crate::config;
+use crate::error::Error;
+use crate::jobs;
+use crate::model::ArchivedGuild;
+use crate::OutputMode;
+use crate::Result;
+
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::NaiveTime;
+use chrono::Utc;
+use chrono::Weekday;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::model::id::ChannelId;
+use serenity::model::id::GuildId;
+use serenity::prelude::Context;
+use tokio::sync::Mutex;
+use tracing::*;
+
+/// How often the scheduler checks for archives that are due.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+static SCHEDULES: OnceCell<Mutex<Schedules>> = OnceCell::new();
+
+/// Whether the scheduler loop has been started. `ready` can be called more than once if the bot
+/// reconnects, and we only want one loop.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn schedules() -> &'static Mutex<Schedules> {
+    SCHEDULES.get().expect("Schedules are loaded at startup")
+}
+
+/// Load the persisted schedules. This must be called before the bot connects.
+pub async fn load() -> Result<()> {
+    let schedules = Schedules::load().await?;
+    info!(count = %schedules.schedules.len(), "Loaded schedules");
+    // This is only called once, from `run_bot`
+    let _ = SCHEDULES.set(Mutex::new(schedules));
+    Ok(())
+}
+
+/// A recurring archive of a channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    pub id: u64,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    /// The channel that a summary is posted to after each run.
+    pub log_channel_id: ChannelId,
+    pub output_mode: OutputMode,
+    /// The day of the week to archive on, or `None` to archive every day.
+    pub weekday: Option<Weekday>,
+    /// The time of day to archive at, always in UTC rather than the guild's configured timezone.
+    pub time: NaiveTime,
+    pub created_at: DateTime<Utc>,
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl Schedule {
+    /// The most recent time at or before `now` that this schedule should have run.
+    fn latest_occurrence(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let mut occurrence = now.date_naive().and_time(self.time).and_utc();
+        if occurrence > now {
+            occurrence -= chrono::Duration::days(1);
+        }
+        if let Some(weekday) = self.weekday {
+            while occurrence.weekday() != weekday {
+                occurrence -= chrono::Duration::days(1);
+            }
+        }
+        occurrence
+    }
+
+    /// Whether an occurrence has passed since this schedule last ran.
+    ///
+    /// Occurrences missed while the bot was not running are caught up (once) when it next starts.
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let occurrence = self.latest_occurrence(now);
+        occurrence > self.last_run.unwrap_or(self.created_at)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "`{}`: <#{}> as `{}` {} at {} UTC, logging to <#{}>",
+            self.id,
+            self.channel_id,
+            self.output_mode,
+            match self.weekday {
+                Some(day) => format!("every {day}"),
+                None => "every day".to_owned(),
+            },
+            self.time.format("%H:%M"),
+            self.log_channel_id,
+        )
+    }
+}
+
+/// The set of schedules, persisted to disk so that they survive restarts.
+#[derive(Serialize, Deserialize, Default)]
+struct Schedules {
+    next_id: u64,
+    schedules: Vec<Schedule>,
+}
+
+impl Schedules {
+    fn path() -> PathBuf {
+        config::get().output_path.join("schedules.json")
+    }
+
+    async fn load() -> Result<Self> {
+        let path = Self::path();
+        match tokio::fs::read_to_string(&path).await {
+            // Refuse to continue on a malformed file rather than overwriting the user's schedules
+            Ok(json) => serde_json::from_str(&json).map_err(|error| {
+                format!("Failed to parse schedules file {path:?}: {error}").into()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(?path, "No schedules file found, starting with no schedules");
+                Ok(Self::default())
+            }
+            Err(error) => Err(format!("Failed to read schedules file {path:?}: {error}").into()),
+        }
+    }
+
+    async fn save(&self) -> Result<()> {
+        let path = Self::path();
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_string_pretty(self)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        trace!(?path, "Saved schedules");
+        Ok(())
+    }
+}
+
+pub async fn add_schedule(
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    log_channel_id: ChannelId,
+    output_mode: OutputMode,
+    weekday: Option<Weekday>,
+    time: NaiveTime,
+) -> Result<Schedule> {
+    let mut schedules = schedules().lock().await;
+    schedules.next_id += 1;
+    let schedule = Schedule {
+        id: schedules.next_id,
+        guild_id,
+        channel_id,
+        log_channel_id,
+        output_mode,
+        weekday,
+        time,
+        created_at: Utc::now(),
+        last_run: None,
+    };
+    schedules.schedules.push(schedule.clone());
+    schedules.save().await?;
+    info!(?schedule, "Added schedule");
+    Ok(schedule)
+}
+
+/// Remove the schedule `id` from `guild_id`, returning it if it existed.
+pub async fn remove_schedule(guild_id: GuildId, id: u64) -> Result<Option<Schedule>> {
+    let mut schedules = schedules().lock().await;
+    let index = schedules
+        .schedules
+        .iter()
+        .position(|s| s.id == id && s.guild_id == guild_id);
+    match index {
+        Some(index) => {
+            let schedule = schedules.schedules.remove(index);
+            schedules.save().await?;
+            info!(?schedule, "Removed schedule");
+            Ok(Some(schedule))
+        }
+        None => Ok(None),
+    }
+}
+
+pub async fn list_schedules(guild_id: GuildId) -> Vec<Schedule> {
+    schedules()
+        .lock()
+        .await
+        .schedules
+        .iter()
+        .filter(|s| s.guild_id == guild_id)
+        .cloned()
+        .collect()
+}
+
+/// Start the loop that runs schedules when they are due, if it is not already running.
+pub fn start(ctx: Context) {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        info!("Scheduler started");
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(error) = run_due(&ctx).await {
+                error!(?error, "Failed to run scheduled archives");
+            }
+        }
+    });
+}
+
+async fn run_due(ctx: &Context) -> Result<()> {
+    let now = Utc::now();
+
+    let due = {
+        let mut schedules = schedules().lock().await;
+        let mut due = Vec::new();
+        for schedule in schedules.schedules.iter_mut() {
+            if schedule.is_due(now) {
+                // Record the run before starting it, so that a failing archive is not retried
+                // every poll.
+                schedule.last_run = Some(now);
+                due.push(schedule.clone());
+            }
+        }
+        if !due.is_empty() {
+            schedules.save().await?;
+        }
+        due
+    };
+
+    for schedule in due {
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            info!(id = %schedule.id, "Running scheduled archive");
+            let message = match run_schedule(&ctx, &schedule).await {
+                Ok(summary) => format!(
+                    "Scheduled archive `{}` of <#{}>:\n{}",
+                    schedule.id, schedule.channel_id, summary
+                ),
+                Err(e @ (Error::Forbidden(_) | Error::Download(_) | Error::Cancelled)) => {
+                    error!(error = ?e, id = %schedule.id, "Scheduled archive failed");
+                    format!(
+                        "Scheduled archive `{}` of <#{}> failed:\n{}",
+                        schedule.id, schedule.channel_id, e
+                    )
+                }
+                Err(e) => {
+                    error!(error = ?e, id = %schedule.id, "Scheduled archive failed");
+                    format!(
+                        "Scheduled archive `{}` of <#{}> failed:\n```\n{:?}\n```",
+                        schedule.id, schedule.channel_id, e
+                    )
+                }
+            };
+            if let Err(error) = schedule.log_channel_id.say(&ctx, message).await {
+                error!(?error, id = %schedule.id, "Failed to post scheduled archive summary");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn run_schedule(ctx: &Context, schedule: &Schedule) -> Result<String> {
+    if !config::get().is_guild_allowed(schedule.guild_id) {
+        return Err(Error::Forbidden(
+            "This bot is not enabled in this server".to_owned(),
+        ));
+    }
+
+    let guild = schedule.guild_id.to_partial_guild(ctx).await?;
+    let channel = schedule
+        .channel_id
+        .to_channel(ctx)
+        .await?
+        .guild()
+        .ok_or_else(|| format!("Channel {} is not a guild channel", schedule.channel_id))?;
+
+    let mut job = jobs::submit(
+        schedule.guild_id,
+        format!("Scheduled archive `{}` of <#{}>", schedule.id, channel.id),
+    );
+    job.start().await?;
+
+    let log = archive(
+        &ctx.http,
+        &channel,
+        &ArchivedGuild::from(&guild),
+        schedule.output_mode,
+        false,
+        None,
+        job.cancellation(),
+    )
+    .await?;
+
+    Ok(archive_response(log))
+}
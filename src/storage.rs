@@ -0,0 +1,215 @@
+use crate::config;
+use crate::Result;
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reqwest::Url;
+use rusty_s3::Bucket;
+use rusty_s3::Credentials;
+use rusty_s3::S3Action;
+use rusty_s3::UrlStyle;
+use tracing::*;
+
+/// How long the signed URLs used to make requests to S3 are valid for.
+const SIGNATURE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Options for storing archives in an S3-compatible object store rather than on disk.
+#[derive(clap::Args, Debug)]
+pub struct StorageOpt {
+    /// Store archives in this S3 bucket instead of the output path, which is then only used for
+    /// the bot's own state. Credentials are read from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`
+    #[clap(long)]
+    pub s3_bucket: Option<String>,
+    /// The endpoint of the S3-compatible service, e.g. `http://localhost:9000` for a local MinIO
+    #[clap(long, default_value = "https://s3.amazonaws.com")]
+    pub s3_endpoint: Url,
+    /// The region of the bucket
+    #[clap(long, default_value = "us-east-1")]
+    pub s3_region: String,
+    /// A prefix added to the key of every object stored, e.g. `archives/`
+    #[clap(long, default_value = "")]
+    pub s3_prefix: String,
+    /// Address the bucket as part of the path (`https://endpoint/bucket/key`), rather than as a
+    /// subdomain. MinIO and many other S3-compatible services need this
+    #[clap(long)]
+    pub s3_path_style: bool,
+}
+
+/// Somewhere files can be written to and read from, by key. Keys are relative paths separated by
+/// `/`.
+#[derive(Debug)]
+pub enum Storage {
+    /// A directory on the local filesystem.
+    Local(PathBuf),
+    /// A bucket in an S3-compatible object store.
+    S3(Box<S3Storage>),
+}
+
+#[derive(Debug)]
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Credentials,
+    prefix: String,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    fn new(options: &StorageOpt, name: String) -> Result<Self> {
+        let style = match options.s3_path_style {
+            true => UrlStyle::Path,
+            false => UrlStyle::VirtualHost,
+        };
+        let bucket = Bucket::new(
+            options.s3_endpoint.clone(),
+            style,
+            name,
+            options.s3_region.clone(),
+        )
+        .map_err(|e| format!("Invalid S3 bucket: {}", e))?;
+        let credentials = Credentials::from_env().ok_or_else(|| {
+            "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set to use S3".to_owned()
+        })?;
+
+        Ok(Self {
+            bucket,
+            credentials,
+            prefix: options.s3_prefix.clone(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl Storage {
+    pub fn local<P: Into<PathBuf>>(root: P) -> Self {
+        Storage::Local(root.into())
+    }
+
+    /// The storage configured by `options`, or the directory `output_path` if no other storage
+    /// is.
+    pub fn from_options(options: &StorageOpt, output_path: &Path) -> Result<Self> {
+        match &options.s3_bucket {
+            Some(name) => Ok(Storage::S3(Box::new(S3Storage::new(
+                options,
+                name.clone(),
+            )?))),
+            None => Ok(Storage::local(output_path)),
+        }
+    }
+
+    /// The storage that archives made from Discord are written to.
+    pub fn output() -> &'static Storage {
+        &config::get().storage
+    }
+
+    /// Where `key` is stored, for showing to the user.
+    pub fn location(&self, key: &str) -> String {
+        match self {
+            Storage::Local(root) => root.join(key).display().to_string(),
+            Storage::S3(s3) => format!("s3://{}/{}", s3.bucket.name(), s3.object(key)),
+        }
+    }
+
+    /// Store `data` under `key`, replacing anything already there.
+    ///
+    /// Local files are written under a temporary name and renamed into place, so that a file is
+    /// never seen partially written.
+    #[instrument(skip(self, data))]
+    pub async fn write<D: Into<Vec<u8>>>(&self, key: &str, data: D) -> Result<()> {
+        match self {
+            Storage::Local(root) => {
+                let path = root.join(key);
+                create_parent(&path).await?;
+                let tmp_path = tmp_path(&path);
+                tokio::fs::write(&tmp_path, data.into()).await?;
+                tokio::fs::rename(&tmp_path, &path).await?;
+            }
+            Storage::S3(s3) => {
+                let object = s3.object(key);
+                let url = s3
+                    .bucket
+                    .put_object(Some(&s3.credentials), &object)
+                    .sign(SIGNATURE_LIFETIME);
+                s3.client
+                    .put(url)
+                    .body(data.into())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        trace!(location = %self.location(key), "Stored file");
+        Ok(())
+    }
+
+    /// Store the local file `source` under `key`, removing `source`.
+    pub async fn write_file(&self, key: &str, source: &Path) -> Result<()> {
+        match self {
+            Storage::Local(root) => {
+                let path = root.join(key);
+                create_parent(&path).await?;
+                tokio::fs::rename(source, &path).await?;
+            }
+            Storage::S3(_) => {
+                self.write(key, tokio::fs::read(source).await?).await?;
+                tokio::fs::remove_file(source).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether anything is stored under `key`.
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        match self {
+            Storage::Local(root) => Ok(tokio::fs::try_exists(root.join(key)).await?),
+            Storage::S3(s3) => {
+                let object = s3.object(key);
+                let url = s3
+                    .bucket
+                    .head_object(Some(&s3.credentials), &object)
+                    .sign(SIGNATURE_LIFETIME);
+                let response = s3.client.head(url).send().await?;
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Ok(false);
+                }
+                response.error_for_status()?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Read the contents of `key`.
+    pub async fn read(&self, key: &str) -> Result<Vec<u8>> {
+        match self {
+            Storage::Local(root) => Ok(tokio::fs::read(root.join(key)).await?),
+            Storage::S3(s3) => {
+                let object = s3.object(key);
+                let url = s3
+                    .bucket
+                    .get_object(Some(&s3.credentials), &object)
+                    .sign(SIGNATURE_LIFETIME);
+                let response = s3.client.get(url).send().await?.error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            }
+        }
+    }
+}
+
+async fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
@@ -0,0 +1,406 @@
+use crate::file;
+use crate::model::snowflake;
+use crate::naming;
+use crate::storage::Storage;
+use crate::Result;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::model::channel::Channel;
+use serenity::model::channel::ChannelType;
+use serenity::model::channel::GuildChannel;
+use serenity::model::channel::PermissionOverwrite;
+use serenity::model::channel::PermissionOverwriteType;
+use serenity::model::guild::Guild;
+use serenity::model::guild::GuildWelcomeChannelEmoji;
+use serenity::model::guild::Role;
+use serenity::model::Permissions;
+use tracing::*;
+
+/// The name of the file describing the guild in a structure backup.
+pub const STRUCTURE_FILE: &str = "structure.json";
+
+/// The version of the format of [`GuildStructure`].
+const STRUCTURE_VERSION: u32 = 1;
+
+/// A backup of everything about a guild except its messages, stored as [`STRUCTURE_FILE`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GuildStructure {
+    pub schema_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub settings: GuildSettings,
+    /// Highest first, as they are shown in Discord. Includes `@everyone`.
+    pub roles: Vec<StructureRole>,
+    /// In the order they are shown in Discord.
+    pub categories: Vec<StructureCategory>,
+    /// Channels that aren't in a category, in the order they are shown in Discord.
+    pub channels: Vec<StructureChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GuildSettings {
+    #[serde(with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(with = "snowflake")]
+    pub owner_id: u64,
+    pub preferred_locale: String,
+    pub verification_level: String,
+    pub default_message_notifications: String,
+    pub explicit_content_filter: String,
+    pub mfa_level: String,
+    pub nsfw_level: String,
+    pub features: Vec<String>,
+    pub boost_tier: String,
+    pub vanity_url_code: Option<String>,
+    #[serde(with = "snowflake::option")]
+    pub afk_channel_id: Option<u64>,
+    /// In seconds.
+    pub afk_timeout: u64,
+    #[serde(with = "snowflake::option")]
+    pub system_channel_id: Option<u64>,
+    #[serde(with = "snowflake::option")]
+    pub rules_channel_id: Option<u64>,
+    #[serde(with = "snowflake::option")]
+    pub public_updates_channel_id: Option<u64>,
+    /// The images below are paths relative to [`STRUCTURE_FILE`], or `None` if the guild doesn't
+    /// have one or it could not be downloaded.
+    pub icon: Option<String>,
+    pub banner: Option<String>,
+    pub splash: Option<String>,
+    pub discovery_splash: Option<String>,
+    pub welcome_screen: Option<WelcomeScreen>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WelcomeScreen {
+    pub description: Option<String>,
+    pub channels: Vec<WelcomeChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WelcomeChannel {
+    #[serde(with = "snowflake")]
+    pub channel_id: u64,
+    pub description: String,
+    /// A unicode emoji, or the name of a custom one.
+    pub emoji: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StructureRole {
+    #[serde(with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+    /// As `#rrggbb`, or `None` for the default colour.
+    pub colour: Option<String>,
+    pub position: i64,
+    /// Whether members with the role are shown separately in the member list.
+    pub hoist: bool,
+    pub mentionable: bool,
+    /// Whether the role is managed by an integration, such as a bot's own role.
+    pub managed: bool,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StructureCategory {
+    #[serde(flatten)]
+    pub category: StructureChannel,
+    pub channels: Vec<StructureChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StructureChannel {
+    #[serde(with = "snowflake")]
+    pub id: u64,
+    pub name: String,
+    /// `text`, `voice`, `category`, `news`, `stage`, `forum`, etc.
+    pub kind: String,
+    pub position: i64,
+    pub topic: Option<String>,
+    pub nsfw: bool,
+    /// How many seconds members must wait between sending messages.
+    pub slowmode: Option<u64>,
+    /// In bits per second, for voice channels.
+    pub bitrate: Option<u64>,
+    /// For voice channels.
+    pub user_limit: Option<u64>,
+    pub permission_overwrites: Vec<StructureOverwrite>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StructureOverwrite {
+    /// `role` or `member`.
+    pub kind: String,
+    #[serde(with = "snowflake")]
+    pub id: u64,
+    /// The name of the role or member, for reference.
+    pub name: Option<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// What [`archive_structure`] saved.
+pub struct StructureArchiveLog {
+    pub roles: usize,
+    pub channels: usize,
+    /// Where the backup was saved, for showing to the user.
+    pub location: String,
+}
+
+/// The names of `permissions`, e.g. `Manage Messages`.
+fn permission_names(permissions: Permissions) -> Vec<String> {
+    permissions
+        .get_permission_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect()
+}
+
+impl From<&Role> for StructureRole {
+    fn from(role: &Role) -> Self {
+        Self {
+            id: role.id.0,
+            name: role.name.clone(),
+            colour: match role.colour.0 {
+                0 => None,
+                colour => Some(format!("#{:06x}", colour)),
+            },
+            position: role.position,
+            hoist: role.hoist,
+            mentionable: role.mentionable,
+            managed: role.managed,
+            permissions: permission_names(role.permissions),
+        }
+    }
+}
+
+impl StructureChannel {
+    fn new(channel: &GuildChannel, guild: &Guild) -> Self {
+        Self {
+            id: channel.id.0,
+            name: channel.name.clone(),
+            kind: channel.kind.name().to_owned(),
+            position: channel.position,
+            topic: channel.topic.clone(),
+            nsfw: channel.nsfw,
+            slowmode: channel.rate_limit_per_user,
+            bitrate: channel.bitrate,
+            user_limit: channel.user_limit,
+            permission_overwrites: channel
+                .permission_overwrites
+                .iter()
+                .map(|x| StructureOverwrite::new(x, guild))
+                .collect(),
+        }
+    }
+}
+
+impl StructureOverwrite {
+    fn new(overwrite: &PermissionOverwrite, guild: &Guild) -> Self {
+        let (kind, id, name) = match overwrite.kind {
+            PermissionOverwriteType::Role(id) => {
+                ("role", id.0, guild.roles.get(&id).map(|x| x.name.clone()))
+            }
+            PermissionOverwriteType::Member(id) => {
+                ("member", id.0, guild.members.get(&id).map(|x| x.user.tag()))
+            }
+            _ => ("unknown", 0, None),
+        };
+        Self {
+            kind: kind.to_owned(),
+            id,
+            name,
+            allow: permission_names(overwrite.allow),
+            deny: permission_names(overwrite.deny),
+        }
+    }
+}
+
+/// Save the roles, channels and settings of `guild`, and its images, to a new directory in
+/// [`Storage::output`].
+#[instrument(skip_all, fields(guild = %guild.name))]
+pub async fn archive_structure(http: &Http, guild: &Guild) -> Result<StructureArchiveLog> {
+    info!("Starting structure archive");
+    let storage = Storage::output();
+    let exported_at = Utc::now();
+    let output_directory = format!(
+        "{}-structure-{}",
+        naming::sanitize(&guild.name.replace(char::is_whitespace, "-").to_lowercase()),
+        exported_at.format("%Y-%m-%dT%H-%M-%S")
+    );
+
+    let mut roles = guild.roles.values().collect::<Vec<_>>();
+    // Roles with the same position are shown in order of ID
+    roles.sort_by_key(|role| (std::cmp::Reverse(role.position), role.id));
+
+    let mut channels = guild
+        .channels
+        .values()
+        .filter_map(|channel| match channel {
+            Channel::Guild(channel) => Some(channel),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // Voice channels are shown below text channels in each category
+    channels.sort_by_key(|channel| {
+        let voice = matches!(channel.kind, ChannelType::Voice | ChannelType::Stage);
+        (voice, channel.position, channel.id)
+    });
+
+    let categories = channels
+        .iter()
+        .filter(|channel| channel.kind == ChannelType::Category)
+        .map(|category| StructureCategory {
+            category: StructureChannel::new(category, guild),
+            channels: channels
+                .iter()
+                .filter(|channel| channel.parent_id == Some(category.id))
+                .map(|channel| StructureChannel::new(channel, guild))
+                .collect(),
+        })
+        .collect();
+    let uncategorised = channels
+        .iter()
+        .filter(|channel| channel.kind != ChannelType::Category && channel.parent_id.is_none())
+        .map(|channel| StructureChannel::new(channel, guild))
+        .collect();
+
+    // The cached guild usually doesn't include the welcome screen
+    let welcome_screen = match guild.id.get_welcome_screen(http).await {
+        Ok(x) => Some(x),
+        Err(error) => {
+            trace!(?error, "No welcome screen");
+            guild.welcome_screen.clone()
+        }
+    };
+
+    let discovery_splash_url = guild.discovery_splash.as_ref().map(|hash| {
+        format!(
+            "https://cdn.discordapp.com/discovery-splashes/{}/{}.png?size=4096",
+            guild.id, hash
+        )
+    });
+    let images = [
+        ("icon", guild.icon_url()),
+        ("banner", guild.banner_url()),
+        ("splash", guild.splash_url()),
+        ("discovery_splash", discovery_splash_url),
+    ];
+    let mut image_paths = Vec::new();
+    for (name, url) in images {
+        image_paths.push(match url {
+            Some(url) => download_image(&url, name, storage, &output_directory).await,
+            None => None,
+        });
+    }
+    let [icon, banner, splash, discovery_splash]: [Option<String>; 4] = image_paths
+        .try_into()
+        .expect("There is a path for each image");
+
+    let structure = GuildStructure {
+        schema_version: STRUCTURE_VERSION,
+        exported_at,
+        settings: GuildSettings {
+            id: guild.id.0,
+            name: guild.name.clone(),
+            description: guild.description.clone(),
+            owner_id: guild.owner_id.0,
+            preferred_locale: guild.preferred_locale.clone(),
+            verification_level: format!("{:?}", guild.verification_level),
+            default_message_notifications: format!("{:?}", guild.default_message_notifications),
+            explicit_content_filter: format!("{:?}", guild.explicit_content_filter),
+            mfa_level: format!("{:?}", guild.mfa_level),
+            nsfw_level: format!("{:?}", guild.nsfw_level),
+            features: guild.features.clone(),
+            boost_tier: format!("{:?}", guild.premium_tier),
+            vanity_url_code: guild.vanity_url_code.clone(),
+            afk_channel_id: guild.afk_channel_id.map(|x| x.0),
+            afk_timeout: guild.afk_timeout,
+            system_channel_id: guild.system_channel_id.map(|x| x.0),
+            rules_channel_id: guild.rules_channel_id.map(|x| x.0),
+            public_updates_channel_id: guild.public_updates_channel_id.map(|x| x.0),
+            icon,
+            banner,
+            splash,
+            discovery_splash,
+            welcome_screen: welcome_screen.map(|screen| WelcomeScreen {
+                description: screen.description,
+                channels: screen
+                    .welcome_channels
+                    .into_iter()
+                    .map(|channel| WelcomeChannel {
+                        channel_id: channel.channel_id.0,
+                        description: channel.description,
+                        emoji: channel.emoji.map(|emoji| match emoji {
+                            GuildWelcomeChannelEmoji::Custom { name, .. } => name,
+                            GuildWelcomeChannelEmoji::Unicode(x) => x,
+                            _ => String::new(),
+                        }),
+                    })
+                    .collect(),
+            }),
+        },
+        roles: roles.into_iter().map(StructureRole::from).collect(),
+        categories,
+        channels: uncategorised,
+    };
+
+    storage
+        .write(
+            &format!("{}/{}", output_directory, STRUCTURE_FILE),
+            serde_json::to_string_pretty(&structure)?,
+        )
+        .await?;
+
+    info!(
+        roles = %structure.roles.len(),
+        channels = %channels.len(),
+        "Structure archive complete"
+    );
+
+    Ok(StructureArchiveLog {
+        roles: structure.roles.len(),
+        channels: channels.len(),
+        location: storage.location(&output_directory),
+    })
+}
+
+/// Download the guild image at `url` to `{name}.{ext}` in `output_directory`, returning its path
+/// relative to it, or `None` if it could not be downloaded.
+async fn download_image(
+    url: &str,
+    name: &str,
+    storage: &Storage,
+    output_directory: &str,
+) -> Option<String> {
+    let result = async {
+        let download = file::download_url(url).await?;
+        // Animated icons and banners are GIFs
+        let extension = match download.content_type.as_deref() {
+            Some("image/gif") => "gif",
+            Some("image/jpeg") => "jpg",
+            Some("image/webp") => "webp",
+            _ => "png",
+        };
+        let path = format!("{}.{}", name, extension);
+        storage
+            .write(&format!("{}/{}", output_directory, path), download.bytes)
+            .await?;
+        Result::Ok(path)
+    }
+    .await;
+
+    match result {
+        Ok(path) => Some(path),
+        Err(e) => {
+            error!(error = ?e, %name, "Failed to download a guild image");
+            None
+        }
+    }
+}
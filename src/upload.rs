@@ -0,0 +1,153 @@
+use crate::storage::Storage;
+use crate::Result;
+
+use std::io::Cursor;
+use std::io::Write;
+
+use serenity::model::channel::AttachmentType;
+use serenity::model::guild::PremiumTier;
+use serenity::model::id::ChannelId;
+use serenity::prelude::Context;
+use tracing::*;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use zip::ZipWriter;
+
+const MIB: u64 = 1024 * 1024;
+
+/// The most attachments Discord allows on a single message.
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+/// The most parts an archive will be split into before giving up on uploading it.
+const MAX_PARTS: usize = 10;
+
+/// Headroom left below the upload limit for the rest of the request.
+const UPLOAD_MARGIN: u64 = 64 * 1024;
+
+/// The largest total size of the attachments on one message in a guild with boost level `tier`.
+pub fn upload_limit(tier: PremiumTier) -> u64 {
+    match tier {
+        PremiumTier::Tier2 => 50 * MIB,
+        PremiumTier::Tier3 => 100 * MIB,
+        _ => 10 * MIB,
+    }
+}
+
+struct Attachment {
+    filename: String,
+    data: Vec<u8>,
+}
+
+/// Upload `files`, keys in [`Storage::output`], as messages in `channel_id`, returning a note for
+/// the response describing what was uploaded.
+///
+/// These are sent as ordinary messages rather than interaction followups, as the interaction's
+/// token expires 15 minutes after the command is used, and the archives worth uploading are
+/// usually the ones that take longer than that.
+///
+/// If the files fit within the guild's upload limit they are attached as they are. Otherwise they
+/// are zipped into `{name}.zip`, which is split into parts `{name}.zip.001`, `{name}.zip.002`, etc.
+/// if it is still too large.
+#[instrument(skip_all)]
+pub async fn upload(
+    ctx: &Context,
+    channel_id: ChannelId,
+    files: &[String],
+    name: &str,
+    tier: PremiumTier,
+) -> Result<String> {
+    let limit = upload_limit(tier) - UPLOAD_MARGIN;
+
+    let mut attachments = Vec::new();
+    for key in files {
+        attachments.push(Attachment {
+            filename: key.rsplit('/').next().unwrap_or(key).to_owned(),
+            data: Storage::output().read(key).await?,
+        });
+    }
+
+    let name = name.to_owned();
+    let messages = tokio::task::spawn_blocking(move || prepare(attachments, &name, limit))
+        .await
+        .expect("Failed to join the task preparing attachments")?;
+
+    let messages = match messages {
+        Some(x) => x,
+        None => {
+            warn!(%limit, "Archive is too large to upload");
+            return Ok(format!(
+                "The archive is too large to upload to Discord, even split into {} parts of {} MiB. \
+                It is only available in the bot's storage.",
+                MAX_PARTS,
+                upload_limit(tier) / MIB,
+            ));
+        }
+    };
+
+    let count = messages.iter().map(Vec::len).sum::<usize>();
+    for attachments in messages {
+        channel_id
+            .send_message(ctx, |builder| {
+                builder.add_files(
+                    attachments
+                        .into_iter()
+                        .map(|attachment| AttachmentType::Bytes {
+                            data: attachment.data.into(),
+                            filename: attachment.filename,
+                        }),
+                )
+            })
+            .await?;
+    }
+
+    info!(%count, "Uploaded archive");
+
+    Ok(format!("Uploaded the archive as {} attachment(s).", count))
+}
+
+/// Group `attachments` into as few messages as possible, each within `limit`, zipping and
+/// splitting them if necessary, or `None` if they cannot be uploaded.
+fn prepare(
+    attachments: Vec<Attachment>,
+    name: &str,
+    limit: u64,
+) -> Result<Option<Vec<Vec<Attachment>>>> {
+    let total = attachments.iter().map(|x| x.data.len() as u64).sum::<u64>();
+    if total <= limit && attachments.len() <= MAX_ATTACHMENTS_PER_MESSAGE {
+        trace!(%total, "Uploading files as they are");
+        return Ok(Some(vec![attachments]));
+    }
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for attachment in &attachments {
+        zip.start_file(&attachment.filename, options)?;
+        zip.write_all(&attachment.data)?;
+    }
+    let zipped = zip.finish()?.into_inner();
+    trace!(%total, zipped = %zipped.len(), "Zipped files");
+
+    let filename = format!("{name}.zip");
+    if zipped.len() as u64 <= limit {
+        return Ok(Some(vec![vec![Attachment {
+            filename,
+            data: zipped,
+        }]]));
+    }
+
+    let parts = zipped.chunks(limit as usize);
+    if parts.len() > MAX_PARTS {
+        return Ok(None);
+    }
+    Ok(Some(
+        parts
+            .enumerate()
+            .map(|(i, part)| {
+                vec![Attachment {
+                    filename: format!("{filename}.{:03}", i + 1),
+                    data: part.to_vec(),
+                }]
+            })
+            .collect(),
+    ))
+}
@@ -0,0 +1,402 @@
+use crate::config;
+use crate::model::snowflake;
+use crate::model::timestamp;
+use crate::model::Archive;
+use crate::model::ArchivedAttachment;
+use crate::model::ArchivedEmbed;
+use crate::model::ArchivedMessage;
+use crate::model::ArchivedRevision;
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde::Serialize;
+use serenity::model::channel::Message;
+use serenity::model::event::MessageUpdateEvent;
+use serenity::model::id::ChannelId;
+use serenity::model::id::GuildId;
+use serenity::model::id::MessageId;
+use serenity::model::id::UserId;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::*;
+
+static WATCHES: OnceCell<Mutex<Watches>> = OnceCell::new();
+
+/// Held while appending to a log, so that lines from concurrent events don't interleave.
+static APPEND: Mutex<()> = Mutex::const_new(());
+
+fn watches() -> &'static Mutex<Watches> {
+    WATCHES.get().expect("Watches are loaded at startup")
+}
+
+/// Load the persisted watches. This must be called before the bot connects.
+pub async fn load() -> Result<()> {
+    let watches = Watches::load().await?;
+    info!(count = %watches.watches.len(), "Loaded watches");
+    // This is only called once, from `run_bot`
+    let _ = WATCHES.set(Mutex::new(watches));
+    Ok(())
+}
+
+/// A channel whose message events are being recorded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Watch {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    /// The user who started watching the channel.
+    pub started_by: UserId,
+    pub started_at: DateTime<Utc>,
+}
+
+impl Watch {
+    pub fn describe(&self) -> String {
+        format!(
+            "<#{}>, since {} UTC (started by <@{}>)",
+            self.channel_id,
+            self.started_at.format("%Y-%m-%d %H:%M"),
+            self.started_by,
+        )
+    }
+}
+
+/// The set of watched channels, persisted to disk so that they survive restarts.
+#[derive(Serialize, Deserialize, Default)]
+struct Watches {
+    watches: Vec<Watch>,
+    /// The IDs of the watched channels, for checking each event quickly.
+    #[serde(skip)]
+    channels: HashSet<ChannelId>,
+}
+
+impl Watches {
+    fn path() -> PathBuf {
+        config::get().output_path.join("watches.json")
+    }
+
+    async fn load() -> Result<Self> {
+        let path = Self::path();
+        let mut watches: Self = match tokio::fs::read_to_string(&path).await {
+            // Refuse to continue on a malformed file rather than overwriting the user's watches
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|error| format!("Failed to parse watches file {path:?}: {error}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    ?path,
+                    "No watches file found, starting with no watched channels"
+                );
+                Self::default()
+            }
+            Err(error) => {
+                return Err(format!("Failed to read watches file {path:?}: {error}").into())
+            }
+        };
+        watches.channels = watches.watches.iter().map(|w| w.channel_id).collect();
+        Ok(watches)
+    }
+
+    async fn save(&mut self) -> Result<()> {
+        self.channels = self.watches.iter().map(|w| w.channel_id).collect();
+        let path = Self::path();
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_string_pretty(self)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        trace!(?path, "Saved watches");
+        Ok(())
+    }
+}
+
+/// One line of a channel's watch log.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WatchRecord {
+    /// When the bot received the event.
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: WatchEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WatchEvent {
+    /// A message was sent.
+    Create { message: Box<ArchivedMessage> },
+    /// A message was changed. Only the fields that Discord sent are recorded; the rest are `None`.
+    ///
+    /// Discord also sends these when it adds link previews to a message, in which case there is no
+    /// `edited_timestamp`.
+    Update {
+        #[serde(with = "snowflake")]
+        message_id: u64,
+        edited_timestamp: Option<DateTime<Utc>>,
+        content: Option<String>,
+        attachments: Option<Vec<ArchivedAttachment>>,
+        embeds: Option<Vec<ArchivedEmbed>>,
+        pinned: Option<bool>,
+    },
+    /// A message was deleted.
+    Delete {
+        #[serde(with = "snowflake")]
+        message_id: u64,
+    },
+}
+
+/// The path of the watch log of `channel_id`. Logs are JSON Lines of [`WatchRecord`]s, oldest
+/// first, and are only ever appended to.
+pub fn log_path(channel_id: ChannelId) -> PathBuf {
+    config::get()
+        .output_path
+        .join("watch")
+        .join(format!("{}.jsonl", channel_id))
+}
+
+/// What the watch log of a channel says about one message.
+#[derive(Default)]
+struct History {
+    /// The message as it was sent, with any later changes applied, if it was sent while the
+    /// channel was watched.
+    message: Option<ArchivedMessage>,
+    /// Each version of the content that was recorded, oldest first.
+    versions: Vec<ArchivedRevision>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Add what the watch log of `archive`'s channel recorded to it: the earlier versions of edited
+/// messages, when messages were deleted, and the messages that were deleted before the archive
+/// was made, which Discord no longer returns. Does nothing if the channel has never been watched.
+#[instrument(skip_all)]
+pub async fn apply_log(archive: &mut Archive) -> Result<()> {
+    let path = log_path(ChannelId(archive.channel.id));
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut histories = BTreeMap::<u64, History>::new();
+    for (number, line) in contents.lines().enumerate() {
+        let record = match serde_json::from_str::<WatchRecord>(line) {
+            Ok(x) => x,
+            Err(error) => {
+                // The last line can be cut short if the bot stopped while writing it
+                warn!(
+                    ?error,
+                    ?path,
+                    line = number + 1,
+                    "Skipping invalid watch log line"
+                );
+                continue;
+            }
+        };
+        match record.event {
+            WatchEvent::Create { message } => {
+                let history = histories.entry(message.id).or_default();
+                history.versions.push(ArchivedRevision {
+                    content: message.content.clone(),
+                    timestamp: message.timestamp,
+                });
+                history.message = Some(*message);
+            }
+            WatchEvent::Update {
+                message_id,
+                edited_timestamp,
+                content,
+                attachments,
+                embeds,
+                pinned,
+            } => {
+                let history = histories.entry(message_id).or_default();
+                if let (Some(content), Some(edited_timestamp)) = (&content, edited_timestamp) {
+                    history.versions.push(ArchivedRevision {
+                        content: content.clone(),
+                        timestamp: edited_timestamp,
+                    });
+                }
+                if let Some(message) = &mut history.message {
+                    message.edited_timestamp = edited_timestamp.or(message.edited_timestamp);
+                    message.content = content.unwrap_or(std::mem::take(&mut message.content));
+                    message.attachments =
+                        attachments.unwrap_or(std::mem::take(&mut message.attachments));
+                    message.embeds = embeds.unwrap_or(std::mem::take(&mut message.embeds));
+                    message.pinned = pinned.unwrap_or(message.pinned);
+                }
+            }
+            WatchEvent::Delete { message_id } => {
+                histories.entry(message_id).or_default().deleted_at = Some(record.at);
+            }
+        }
+    }
+
+    let mut edited = 0;
+    for message in &mut archive.messages {
+        if let Some(history) = histories.remove(&message.id) {
+            message.revisions = revisions(history.versions, &message.content);
+            message.deleted_at = history.deleted_at;
+            edited += usize::from(!message.revisions.is_empty());
+        }
+    }
+
+    // Whatever is left and was deleted isn't in the archive, as Discord no longer has it
+    let mut deleted = 0;
+    for history in histories.into_values() {
+        let (Some(mut message), Some(deleted_at)) = (history.message, history.deleted_at) else {
+            continue;
+        };
+        message.revisions = revisions(history.versions, &message.content);
+        message.deleted_at = Some(deleted_at);
+        if !archive
+            .users
+            .iter()
+            .any(|user| user.id == message.author.id)
+        {
+            archive.users.push(message.author.clone());
+        }
+        archive.messages.push(message);
+        deleted += 1;
+    }
+    archive.messages.sort_by_key(|message| message.id);
+
+    info!(%edited, %deleted, "Applied watch log");
+    Ok(())
+}
+
+/// The earlier versions of a message whose content is now `current`, given every version recorded.
+fn revisions(mut versions: Vec<ArchivedRevision>, current: &str) -> Vec<ArchivedRevision> {
+    // Edits to attachments or embeds alone repeat the content
+    versions.dedup_by(|a, b| a.content == b.content);
+    if versions.last().is_some_and(|x| x.content == current) {
+        versions.pop();
+    }
+    versions
+}
+
+/// Start recording the message events of `channel_id`, returning `None` if it is already watched.
+pub async fn add_watch(
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    started_by: UserId,
+) -> Result<Option<Watch>> {
+    let mut watches = watches().lock().await;
+    if watches.channels.contains(&channel_id) {
+        return Ok(None);
+    }
+    let watch = Watch {
+        guild_id,
+        channel_id,
+        started_by,
+        started_at: Utc::now(),
+    };
+    watches.watches.push(watch.clone());
+    watches.save().await?;
+    info!(?watch, "Added watch");
+    Ok(Some(watch))
+}
+
+/// Stop recording the message events of `channel_id` in `guild_id`, returning the watch if it
+/// existed. The log is kept.
+pub async fn remove_watch(guild_id: GuildId, channel_id: ChannelId) -> Result<Option<Watch>> {
+    let mut watches = watches().lock().await;
+    let index = watches
+        .watches
+        .iter()
+        .position(|w| w.channel_id == channel_id && w.guild_id == guild_id);
+    match index {
+        Some(index) => {
+            let watch = watches.watches.remove(index);
+            watches.save().await?;
+            info!(?watch, "Removed watch");
+            Ok(Some(watch))
+        }
+        None => Ok(None),
+    }
+}
+
+pub async fn list_watches(guild_id: GuildId) -> Vec<Watch> {
+    watches()
+        .lock()
+        .await
+        .watches
+        .iter()
+        .filter(|w| w.guild_id == guild_id)
+        .cloned()
+        .collect()
+}
+
+/// Record that `message` was sent, if its channel is watched.
+pub async fn record_create(message: &Message) {
+    record(
+        message.channel_id,
+        WatchEvent::Create {
+            message: Box::new(ArchivedMessage::from(message)),
+        },
+    )
+    .await;
+}
+
+/// Record that a message was changed, if its channel is watched.
+pub async fn record_update(event: &MessageUpdateEvent) {
+    record(
+        event.channel_id,
+        WatchEvent::Update {
+            message_id: event.id.0,
+            edited_timestamp: event.edited_timestamp.map(timestamp),
+            content: event.content.clone(),
+            attachments: event
+                .attachments
+                .as_ref()
+                .map(|x| x.iter().map(ArchivedAttachment::from).collect()),
+            embeds: event
+                .embeds
+                .as_ref()
+                .map(|x| x.iter().map(ArchivedEmbed::from).collect()),
+            pinned: event.pinned,
+        },
+    )
+    .await;
+}
+
+/// Record that `message_ids` were deleted, if their channel is watched.
+pub async fn record_delete(channel_id: ChannelId, message_ids: &[MessageId]) {
+    for id in message_ids {
+        record(channel_id, WatchEvent::Delete { message_id: id.0 }).await;
+    }
+}
+
+/// Append `event` to the log of `channel_id` if it is watched. Failures are logged rather than
+/// returned, as there is no one to report them to.
+async fn record(channel_id: ChannelId, event: WatchEvent) {
+    if !watches().lock().await.channels.contains(&channel_id) {
+        return;
+    }
+
+    let record = WatchRecord {
+        at: Utc::now(),
+        event,
+    };
+    trace!(%channel_id, ?record, "Recording watch event");
+    if let Err(error) = append(&log_path(channel_id), &record).await {
+        error!(?error, %channel_id, "Failed to record watch event");
+    }
+}
+
+async fn append(path: &Path, record: &WatchRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    let _lock = APPEND.lock().await;
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}